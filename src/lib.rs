@@ -1,23 +1,49 @@
 //! An [mdBook](https://github.com/rust-lang/mdBook) preprocessor for automatically numbering centered equations.
 
-use log::warn;
+use lazy_static::lazy_static;
+use log::{debug, warn};
 use mdbook::book::{Book, BookItem};
-use mdbook::errors::Result;
+use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use pathdiff::diff_paths;
 use regex::Regex;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 
+/// Applies `func` to all `items` and their sub-items, in the order of `SUMMARY.md`:
+/// each chapter is visited before its sub-chapters, which are visited before the next chapter.
 pub fn for_each_mut_ordered<'a, F, I>(func: &mut F, items: I)
 where
     F: FnMut(&mut BookItem),
     I: IntoIterator<Item = &'a mut BookItem>,
+{
+    for_each_mut_ordered_with_depth(&mut |item: &mut BookItem, _| func(item), items);
+}
+
+/// Same as `for_each_mut_ordered`, additionally passing to `func` the nesting depth of each item
+/// (0 for `items`, 1 for their sub-items, etc.).
+pub fn for_each_mut_ordered_with_depth<'a, F, I>(func: &mut F, items: I)
+where
+    F: FnMut(&mut BookItem, usize),
+    I: IntoIterator<Item = &'a mut BookItem>,
+{
+    visit_at_depth(func, items, 0);
+}
+
+/// Applies `func` to `items` at nesting depth `depth` and to their sub-items at the next depths.
+fn visit_at_depth<'a, F, I>(func: &mut F, items: I, depth: usize)
+where
+    F: FnMut(&mut BookItem, usize),
+    I: IntoIterator<Item = &'a mut BookItem>,
 {
     for item in items {
-        func(item);
+        func(item, depth);
         if let BookItem::Chapter(ch) = item {
-            for_each_mut_ordered(func, &mut ch.sub_items);
+            visit_at_depth(func, &mut ch.sub_items, depth + 1);
         }
     }
 }
@@ -25,244 +51,6336 @@ where
 /// The preprocessor name.
 const NAME: &str = "numeq";
 
+lazy_static! {
+    // see https://regex101.com/ for an explanation of the regexes
+    /// The regex matching equation markers `{{numeq}}{mylabel}{mycaption}[key=value, ...]`
+    /// (or `{{numeq:argument}}...`),
+    /// where braces can be escaped as `\{` and `\}` in the label and caption.
+    static ref EQ_MARKER: Regex = Regex::new(
+        r"\{\{numeq(?::(?P<arg>[^{}\n]*))?\}\}(\{(?P<label>(?:\\.|[^\\}\n])*)\}(\{(?P<caption>(?:\\.|[^\\}\n])*)\})?(\[(?P<meta>[^\]\n]*)\])?)?"
+    )
+    .unwrap();
+    /// The regex matching references `{{eqref: mylabel}}` and `{{cref: mylabel}}`.
+    static ref REF_MARKER: Regex =
+        Regex::new(r"\{\{(?P<kind>eqref|cref):\s*(?P<label>(?:\\.|[^\\\n])*?)\}\}").unwrap();
+    /// The regex matching blank lines separating Markdown blocks.
+    static ref BLANK_LINES: Regex = Regex::new(r"\n[ \t]*\n").unwrap();
+    /// The regex matching Markdown links `[text](url)`.
+    static ref MD_LINK: Regex = Regex::new(r"\[(?P<text>[^\]]*)\]\([^)]*\)").unwrap();
+    /// The regex matching markers and references whose braces were escaped as HTML entities
+    /// (e.g., by a preprocessor running before this one).
+    static ref ESCAPED_MARKER: Regex =
+        Regex::new(r"(?:&#123;|&#x7[bB];|&lbrace;){2}\s*(?:numeq|eqref|cref)").unwrap();
+    /// The regex matching the beginnings of markers (up to their label) and references (up to their labels).
+    static ref MARKER_OPENING: Regex =
+        Regex::new(r"\{\{numeq(?::[^{}\n]*)?\}\}|\{\{(?:eqref|cref):").unwrap();
+    /// The regex matching the opening delimiters of display math blocks, `$$`, `\[` and `\\[`.
+    static ref MATH_OPENING: Regex = Regex::new(r"\$\$|\\?\\\[").unwrap();
+    /// The regex matching manual equation tags `\tag{...}` and `\tag*{...}`.
+    static ref MANUAL_TAG: Regex = Regex::new(r"\\tag\*?\{").unwrap();
+    /// The regex matching numbering regions `{{numeq-begin}} ... {{numeq-end}}`.
+    static ref NUMBERING_REGION: Regex =
+        Regex::new(r"(?s)\{\{numeq-begin\}\}(?P<region>.*?)\{\{numeq-end\}\}").unwrap();
+    /// The regex matching chapter-level directives `<!-- numeq: directive -->`, with the line break following them.
+    static ref DIRECTIVE: Regex =
+        Regex::new(r"<!--\s*numeq:\s*(?P<directive>.*?)\s*-->(?:\r?\n)?").unwrap();
+}
+
+/// The minimum value of option `max_label_len`, the length of the dash and hash ending truncated labels.
+const MIN_LABEL_LEN: usize = 9;
+
+/// The default format of the link text of equation references.
+const DEFAULT_REF_FORMAT: &str = "({n})";
+
+/// The environment variables overriding options of `book.toml`, and the options they override.
+const ENV_OVERRIDES: [(&str, &str); 8] = [
+    ("NUMEQ_PREFIX", "prefix"),
+    ("NUMEQ_DEPTH", "depth"),
+    ("NUMEQ_RESET", "reset"),
+    ("NUMEQ_GLOBAL", "global"),
+    ("NUMEQ_STYLE", "first_component_style"),
+    ("NUMEQ_REF_FORMAT", "ref_format"),
+    ("NUMEQ_OUTPUT", "output"),
+    ("NUMEQ_STRICT", "strict"),
+];
+
 /// A preprocessor for automatically numbering centered equations.
-#[derive(Default)]
+///
+/// Running the preprocessor on an already processed book is a no-op, since all `{{numeq}}`
+/// and `{{eqref}}` markers are consumed by the first run.
 pub struct NumEqPreprocessor {
     /// Whether equation numbers must be prefixed by the section number.
     with_prefix: bool,
     prefix_depth: usize,
-    global: bool,
+    /// Whether the prefix is made of the last `prefix_depth` components of the section number
+    /// rather than the first ones.
+    depth_from_bottom: bool,
+    /// The number of leading components of the section number whose change resets the counter
+    /// (by default, the displayed components).
+    reset_depth: Option<usize>,
+    /// When the equation counter is reset.
+    reset: Reset,
+    /// Whether unnumbered chapters after the numbered ones are appendices, with their own counters and letter prefixes.
+    appendices: bool,
+    /// The format of the link text of equation references, where `{n}` stands for the equation number
+    /// `{section}` for the section number of the chapter containing the equation and `{title}` for its name.
+    ref_format: String,
+    /// The formats overriding `ref_format` for references to equations of the same chapter and of other chapters.
+    ref_format_local: Option<String>,
+    ref_format_remote: Option<String>,
+    /// Whether equations are only numbered inside `{{numeq-begin}} ... {{numeq-end}}` regions.
+    regions: bool,
+    /// Whether to warn when the same equation number is displayed in several chapters.
+    warn_collisions: bool,
+    /// How equation numbers are output.
+    output: Output,
+    /// Whether the link text of references includes the caption of the equation, if any.
+    ref_with_caption: bool,
+    /// The string replacing colons in anchor ids, if any.
+    colon_replacement: Option<String>,
+    /// The word preceding a `cref` reference to a single equation.
+    cref_singular: String,
+    /// The word preceding a `cref` reference to several equations.
+    cref_plural: String,
+    /// The conjunction between the last two items of a list of references.
+    list_conjunction: String,
+    /// Whether a comma precedes the conjunction in lists of three references or more.
+    oxford_comma: bool,
+    /// Whether to warn about references to an equation defined in the same block.
+    warn_self_ref: bool,
+    /// What the prefix is derived from.
+    prefix_source: PrefixSource,
+    /// How anchors to labeled equations are output.
+    anchor_style: AnchorStyle,
+    /// The CSS class of `<span>` and `<a>` anchors (none if empty).
+    anchor_class: String,
+    /// The position of equation numbers.
+    number_position: NumberPosition,
+    /// How markers without label are treated.
+    bare: Bare,
+    /// Whether numbered math blocks are wrapped in a `<div>` element carrying the anchor.
+    wrap_block: bool,
+    /// The style of the first displayed component of the prefix.
+    first_component_style: ComponentStyle,
+    /// Whether unlabeled equations get an anchor with an id derived from their number.
+    auto_anchor: bool,
+    /// Whether anchor ids are derived from the position of equations in the book instead of their labels.
+    stable_ids: bool,
+    /// Whether markers inside HTML comments are left untouched.
+    skip_comments: bool,
+    /// Whether equations in table rows are numbered with text appended to the math block rather than `\tag`.
+    table_inline_numbers: bool,
+    /// Whether content containing manual `\tag` commands is left untouched (rather than numbered with a warning).
+    skip_manual_tags: bool,
+    /// The path of the file persisting the label map between runs, if any
+    /// (the option being relative to the book root).
+    cache_path: Option<PathBuf>,
+    /// The path (relative to the book root) of the file where the chapters referencing each label are written, if any.
+    backref_map: Option<PathBuf>,
+    /// The style of equation references.
+    ref_style: RefStyle,
+    /// Whether labels and references within a chapter are emitted as MathJax `\label` and `\eqref` commands.
+    mathjax_native_refs: bool,
+    /// The number of lines (in em units) by which the anchors of equations are shifted upwards, if positive.
+    anchor_offset: usize,
+    /// Whether a visually hidden text announcing the equation number is added before each math block.
+    a11y: bool,
+    /// Whether a hidden text with the equation number is added before each math block for the search index.
+    searchable: bool,
+    /// Whether the dot ending the prefix is kept between the prefix and the counter (otherwise, the counter is parenthesized).
+    prefix_trailing_dot: bool,
+    /// The separator between the prefix and the counter (replacing the dot ending the prefix).
+    counter_separator: String,
+    /// The separators between the components of the prefix, the last one being used for extra components
+    /// (dots if empty).
+    prefix_separators: Vec<String>,
+    /// The separator between the endpoints of a range of references.
+    range_separator: String,
+    /// The format of ranges of references, with placeholders `{range}` and `{count}`, if any.
+    ref_format_range: Option<String>,
+    /// Whether every numbered equation must have a label.
+    require_labels: bool,
+    /// Whether policy violations (such as unlabeled equations with `require_labels`) make the build fail
+    /// rather than emitting warnings.
+    strict: bool,
+    /// The maximum length of labels, beyond which they are truncated with a hash suffix.
+    max_label_len: Option<usize>,
+    /// Labels defined outside of the book, which references can resolve to.
+    external_refs: HashMap<String, LabelInfo>,
+    /// Whether equations of the book can redefine external labels.
+    override_external: bool,
+    /// Whether counters continue from the highest numbers of external equations with the same prefix.
+    continue_from_import: bool,
+    /// The maximal distance (in bytes) between two references to the same equation for the second one to be
+    /// displayed without link (0 to always link).
+    collapse_repeats: usize,
+    /// Whether runs of at least three consecutive equations in lists of references are displayed as ranges.
+    collapse_ranges: bool,
+    /// Whether repeated items of lists of references are dropped.
+    dedup_refs: bool,
+    /// Whether the book is written in a right-to-left language.
+    rtl: bool,
+    /// The prefixes of equations of specific chapters, by path.
+    chapter_prefixes: HashMap<PathBuf, String>,
+    /// The level of the headings at which the counter is reset within chapters, if any.
+    reset_on_heading: Option<usize>,
+    /// The prefix of unnumbered chapters when prefixes are enabled.
+    fallback_prefix: String,
+    /// The names of the streams of equations numbered independently, with markers `{{numeq:name}}`.
+    streams: Vec<String>,
+    /// Whether markers outside of math blocks make the build fail.
+    require_math: bool,
+    /// The pattern which labels must match, if any.
+    label_pattern: Option<Regex>,
+    /// Whether only the first marker of each math block is numbered.
+    one_per_block: bool,
+    /// Whether labels are displayed next to the numbers in `\tag`, for debugging.
+    debug_labels: bool,
+    /// The format of the warnings located in chapters, for continuous integration services.
+    ci: CiFormat,
+    /// Whether the name of the target chapter is appended to references to equations of other chapters.
+    show_chapter_on_crosspage: bool,
+    /// Whether balanced braces are allowed in labels without escaping them.
+    nested_braces: bool,
+    /// The CSS class of references, which are then HTML links instead of Markdown links, if any.
+    ref_class: Option<String>,
+    /// The transformation applied to equation numbers.
+    number_transform: NumberTransform,
+    /// Whether the lines of markers removed from the output are kept (empty) rather than removed.
+    preserve_layout: bool,
+    /// The renderers for which markers are stripped instead of being numbered.
+    strip_renderers: Vec<String>,
+    /// The source directory of the book, relative to its root (for annotations of continuous integration services).
+    src_dir: PathBuf,
+}
+
+/// The styles of anchors to labeled equations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AnchorStyle {
+    /// A KaTeX `\htmlId` command inside the equation.
+    HtmlId,
+    /// An empty `<span id="...">` element before the math block.
+    Span,
+    /// An empty `<a name="...">` element before the math block, for legacy browsers.
+    AName,
+}
+
+/// The events resetting the equation counter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reset {
+    /// The counter is never reset.
+    Never,
+    /// The counter is reset for each (sub-)chapter.
+    Chapter,
+    /// The counter is reset for each part of the book.
+    Part,
+    /// The counter is reset when the prefix (limited to `depth` components) changes.
+    Depth,
+}
+
+/// The treatments of markers without label.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Bare {
+    /// The equation is numbered as usual.
+    Number,
+    /// The marker is left untouched.
+    Ignore,
+    /// The marker is removed, without numbering the equation.
+    Remove,
+}
+
+/// The transformations of equation numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NumberTransform {
+    /// Numbers are left as is.
+    None,
+    /// Runs of more than four digits are split into groups of three digits by thin spaces.
+    ThinSpaceGroups,
+    /// Letters are uppercased.
+    Upper,
+}
+
+/// The formats of warnings located in chapters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CiFormat {
+    /// Plain messages.
+    Plain,
+    /// GitHub Actions annotations `::warning file=...::message`.
+    Github,
+}
+
+/// The styles of section number components.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ComponentStyle {
+    /// Arabic numerals: 1, 2, 3, etc.
+    Arabic,
+    /// Uppercase Roman numerals: I, II, III, etc.
+    Roman,
+    /// Uppercase letters: A, B, C, etc.
+    Alpha,
+}
+
+/// The positions of equation numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NumberPosition {
+    /// A `\tag` in the equation.
+    Tag,
+    /// A bold line above the math block.
+    Above,
+}
+
+/// The styles of equation references.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RefStyle {
+    /// An inline link to the equation.
+    Link,
+    /// A footnote reference, the link being in the footnote definition appended to the chapter.
+    Footnote,
+}
+
+/// The sources of equation number prefixes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PrefixSource {
+    /// The section number of the chapter.
+    Section,
+    /// A slug of the chapter name.
+    Slug,
+    /// The 1-based position of the chapter in the book.
+    Index,
+}
+
+/// The ways of outputting equation numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Output {
+    /// A `\tag` command inside the equation.
+    Tag,
+    /// A `data-eqnum` attribute on an element wrapping the equation.
+    DataAttr,
+}
+
+impl Default for NumEqPreprocessor {
+    fn default() -> Self {
+        Self {
+            with_prefix: false,
+            prefix_depth: 0,
+            depth_from_bottom: false,
+            reset_depth: None,
+            reset: Reset::Chapter,
+            appendices: false,
+            ref_format: DEFAULT_REF_FORMAT.to_string(),
+            ref_format_local: None,
+            ref_format_remote: None,
+            regions: false,
+            warn_collisions: false,
+            output: Output::Tag,
+            ref_with_caption: false,
+            colon_replacement: None,
+            cref_singular: "Equation".to_string(),
+            cref_plural: "Equations".to_string(),
+            list_conjunction: "and".to_string(),
+            oxford_comma: false,
+            warn_self_ref: false,
+            prefix_source: PrefixSource::Section,
+            anchor_style: AnchorStyle::HtmlId,
+            anchor_class: "numeq-anchor".to_string(),
+            number_position: NumberPosition::Tag,
+            bare: Bare::Number,
+            wrap_block: false,
+            first_component_style: ComponentStyle::Arabic,
+            auto_anchor: false,
+            stable_ids: false,
+            skip_comments: true,
+            table_inline_numbers: false,
+            skip_manual_tags: false,
+            cache_path: None,
+            backref_map: None,
+            ref_style: RefStyle::Link,
+            mathjax_native_refs: false,
+            anchor_offset: 0,
+            a11y: false,
+            searchable: false,
+            prefix_trailing_dot: true,
+            counter_separator: ".".to_string(),
+            prefix_separators: Vec::new(),
+            range_separator: "\u{2013}".to_string(),
+            ref_format_range: None,
+            require_labels: false,
+            strict: false,
+            max_label_len: None,
+            external_refs: HashMap::new(),
+            override_external: false,
+            continue_from_import: false,
+            streams: Vec::new(),
+            require_math: false,
+            label_pattern: None,
+            one_per_block: false,
+            debug_labels: false,
+            ci: CiFormat::Plain,
+            show_chapter_on_crosspage: false,
+            nested_braces: false,
+            ref_class: None,
+            number_transform: NumberTransform::None,
+            preserve_layout: false,
+            strip_renderers: Vec::new(),
+            src_dir: PathBuf::from("src"),
+            fallback_prefix: String::new(),
+            reset_on_heading: None,
+            chapter_prefixes: HashMap::new(),
+            rtl: false,
+            collapse_repeats: 0,
+            collapse_ranges: false,
+            dedup_refs: false,
+        }
+    }
 }
 
 /// The `LabelInfo` structure contains information for formatting the hyperlink to a specific equation.
-#[derive(Debug, PartialEq)]
-struct LabelInfo {
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LabelInfo {
     /// The number associated with the labeled equation.
-    num: String,
+    pub num: String,
     /// The path to the file containing the environment with the label.
-    path: PathBuf,
+    pub path: PathBuf,
+    /// The caption of the labeled equation, if any.
+    pub caption: Option<String>,
+    /// The section number (without trailing dot) of the chapter containing the labeled equation, if any.
+    pub section: Option<String>,
+    /// The name (without Markdown formatting) of the chapter containing the labeled equation, if known.
+    pub title: Option<String>,
+    /// The metadata key-values of the labeled equation, which are ignored by rendering.
+    pub meta: HashMap<String, String>,
+    /// The id of the anchor of the labeled equation, if it is not derived from the label.
+    pub id: Option<String>,
+    /// The absolute URL of the labeled equation, if it is outside of the book (e.g., in a PDF document).
+    pub url: Option<String>,
+}
+
+/// The `Equation` structure records a numbered equation of a chapter, in order of appearance.
+#[derive(Debug, PartialEq)]
+struct Equation {
+    /// The position (line and column) of the marker of the equation in the chapter.
+    pos: (usize, usize),
+    /// The number of the equation.
+    num: String,
+    /// The label of the equation, if it has one which can be linked to.
+    label: Option<String>,
+    /// The label of the equation, if it was ignored because it was already used.
+    duplicate: Option<String>,
+}
+
+/// The `Counters` structure holds the equation counter and the counters of named streams.
+#[derive(Debug, Default, PartialEq)]
+struct Counters {
+    /// The counter of equations outside of named streams.
+    main: usize,
+    /// The counters of the named streams, which are reset together with the main counter.
+    streams: HashMap<String, usize>,
+    /// The number of equations numbered so far in the book, which is never reset.
+    global: usize,
+}
+
+impl Counters {
+    /// Resets all counters (except the global one).
+    fn reset(&mut self) {
+        self.main = 0;
+        self.streams.clear();
+    }
+}
+
+/// The kinds of issues reported by `NumEqPreprocessor::process_chapter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiagnosticKind {
+    /// A label already used by a previous equation.
+    DuplicateLabel,
+    /// An equation without label while `require_labels` is set.
+    MissingLabel,
+    /// A reference to an unknown label.
+    UnresolvedReference,
+}
+
+/// The `Diagnostic` structure describes an issue found while processing a chapter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// The kind of issue.
+    pub kind: DiagnosticKind,
+    /// The label concerned, if any.
+    pub label: Option<String>,
+    /// A human-readable description of the issue.
+    pub message: String,
+}
+
+/// The `NumEqSummary` structure gathers statistics about a run of the preprocessor.
+#[derive(Debug, Default, PartialEq)]
+pub struct NumEqSummary {
+    /// The total number of numbered equations.
+    pub total: usize,
+    /// The number of labeled equations (which can be referenced).
+    pub labeled: usize,
+    /// The number of references found.
+    pub references: usize,
+    /// The unresolved references, with the path of the chapter they appear in.
+    pub unresolved: Vec<(String, PathBuf)>,
+    /// The labels which were used more than once, with the path of the chapter of each repeated use.
+    pub duplicates: Vec<(String, PathBuf)>,
+    /// The paths of the chapters referencing each label, in order of processing.
+    pub backrefs: BTreeMap<String, Vec<PathBuf>>,
+    /// The label map, i.e., the information about each label.
+    pub labels: HashMap<String, LabelInfo>,
+}
+
+impl NumEqSummary {
+    /// Records a reference to `label` from the chapter at path `from`.
+    fn record_backref(&mut self, label: &str, from: &Path) {
+        let paths = self.backrefs.entry(label.to_string()).or_default();
+        if !paths.iter().any(|p| p == from) {
+            paths.push(from.to_path_buf());
+        }
+    }
 }
 
 impl NumEqPreprocessor {
     pub fn new(ctx: &PreprocessorContext) -> Self {
+        Self::new_with_env(ctx, |var| std::env::var(var).ok())
+    }
+
+    /// Creates the preprocessor from the configuration of `ctx`, whose options listed in `ENV_OVERRIDES`
+    /// are overridden by the environment variables found by `env`.
+    fn new_with_env<F: Fn(&str) -> Option<String>>(ctx: &PreprocessorContext, env: F) -> Self {
         let mut preprocessor = Self::default();
 
-        if let Some(toml::Value::Boolean(b)) = ctx.config.get("preprocessor.numeq.prefix") {
-            preprocessor.with_prefix = *b;
+        let mut config = ctx.config.clone();
+        for (var, option) in ENV_OVERRIDES {
+            if let Some(value) = env(var) {
+                // values are read as TOML values, falling back to strings (e.g., `NUMEQ_STYLE=roman`)
+                let value = toml::from_str::<toml::Value>(&format!("value = {value}"))
+                    .ok()
+                    .and_then(|t| t.get("value").cloned())
+                    .unwrap_or(toml::Value::String(value));
+                debug!("Option {option} set to {value} by environment variable {var}");
+                if let Err(e) = config.set(format!("preprocessor.numeq.{option}"), value) {
+                    warn!("Ignoring environment variable {var}: {e}");
+                }
+            }
         }
 
-        if let Some(toml::Value::Integer(d)) = ctx.config.get("preprocessor.numeq.depth") {
-            preprocessor.prefix_depth = *d as usize;
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.prefix") {
+            preprocessor.with_prefix = *b;
         }
 
-        if let Some(toml::Value::Boolean(b)) = ctx.config.get("preprocessor.numeq.global") {
-            preprocessor.global = *b;
+        if let Some(d) = get_usize(&config, "depth") {
+            preprocessor.prefix_depth = d;
         }
 
-        preprocessor
-    }
-}
+        if let Some(d) = get_usize(&config, "show_depth") {
+            preprocessor.prefix_depth = d;
+        }
 
-impl Preprocessor for NumEqPreprocessor {
-    fn name(&self) -> &str {
-        NAME
-    }
+        if let Some(d) = get_usize(&config, "reset_depth") {
+            preprocessor.reset_depth = Some(d);
+        }
 
-    fn run(&self, _ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        // a hashmap mapping labels to `LabelInfo` structs
-        let mut refs: HashMap<String, LabelInfo> = HashMap::new();
-        // equation counter
-        let mut ctr = 0;
-        // store current (sub-)chapter number according to the depth
-        // initialize with one 1 followed by (prefix_depth - 1) zeros
-        let mut ccn: Vec<usize> = vec![1];
-        ccn.resize(self.prefix_depth, 0);
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.depth_from") {
+            match f.as_str() {
+                "top" => preprocessor.depth_from_bottom = false,
+                "bottom" => preprocessor.depth_from_bottom = true,
+                _ => warn!("Unknown value `{f}' for option depth_from, expected `top' or `bottom'"),
+            }
+        }
 
-        for_each_mut_ordered(
-            &mut |item: &mut BookItem| {
-                if let BookItem::Chapter(chapter) = item {
-                    if !chapter.is_draft_chapter() {
-                        // one can safely unwrap chapter.path which must be Some(...)
-                        let mut prefix = if self.with_prefix {
-                            match &chapter.number {
-                                Some(sn) => sn.to_string(),
-                                None => String::new(),
-                            }
-                        } else {
-                            String::new()
-                        };
-                        let path = chapter.path.as_ref().unwrap();
-                        // reset counter if global counting is set to false
-                        if !self.global && self.prefix_depth == 0 {
-                            ctr = 0;
-                        }
-                        if self.prefix_depth > 0 {
-                            if prefix.is_empty() {
-                                // if prefix is empty, reset counter
-                                ctr = 0;
-                            } else {
-                                // obtain the chapter number as vector of usize
-                                let mut prefix_vec: Vec<usize> = prefix
-                                    .trim_end_matches('.')
-                                    .split('.')
-                                    .map(|s| s.parse::<usize>().unwrap())
-                                    .collect::<Vec<usize>>();
-                                if prefix_vec.len() < self.prefix_depth {
-                                    prefix_vec.resize(self.prefix_depth, 0);
-                                }
-                                // if ccn is different from the specifier in prefix_vec, update ccn
-                                if ccn[..] != prefix_vec[..self.prefix_depth] {
-                                    ccn.copy_from_slice(&prefix_vec[..self.prefix_depth]);
-                                    // reset counter
-                                    ctr = 0;
-                                }
-                                // update prefix
-                                prefix = ccn
-                                    .iter()
-                                    .fold(String::new(), |acc, x| acc + &x.to_string() + ".");
-                            }
-                        }
-                        chapter.content = find_and_replace_eqs(
-                            &chapter.content,
-                            &prefix,
-                            path,
-                            &mut refs,
-                            &mut ctr,
-                        );
-                    }
-                }
-            },
-            &mut book.sections,
+        // the implicit reset semantics of the `global` and `depth` options
+        let global = matches!(
+            config.get("preprocessor.numeq.global"),
+            Some(toml::Value::Boolean(true))
         );
+        preprocessor.reset = if preprocessor.prefix_depth > 0 || preprocessor.reset_depth.is_some()
+        {
+            Reset::Depth
+        } else if global {
+            Reset::Never
+        } else {
+            Reset::Chapter
+        };
 
-        book.for_each_mut(|item: &mut BookItem| {
-            if let BookItem::Chapter(chapter) = item {
-                if !chapter.is_draft_chapter() {
-                    // one can safely unwrap chapter.path which must be Some(...)
-                    let path = chapter.path.as_ref().unwrap();
-                    chapter.content = find_and_replace_refs(&chapter.content, path, &refs);
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.reset") {
+            match f.as_str() {
+                "never" => preprocessor.reset = Reset::Never,
+                "chapter" => preprocessor.reset = Reset::Chapter,
+                "part" => preprocessor.reset = Reset::Part,
+                "depth" => preprocessor.reset = Reset::Depth,
+                _ => warn!(
+                    "Unknown value `{f}' for option reset, expected `never', `chapter', `part' or `depth'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.appendices") {
+            preprocessor.appendices = *b;
+        }
+
+        match config.get("preprocessor.numeq.ref_format") {
+            Some(toml::Value::String(f)) => preprocessor.ref_format = f.clone(),
+            // a table of formats keyed by language, from which the one of the book is selected
+            Some(toml::Value::Table(formats)) => {
+                let language = ctx.config.book.language.as_deref().unwrap_or("en");
+                match formats.get(language) {
+                    Some(toml::Value::String(f)) => preprocessor.ref_format = f.clone(),
+                    _ => warn!(
+                        "No reference format for language `{language}' in option ref_format, using the default one"
+                    ),
                 }
             }
-        });
+            _ => (),
+        }
 
-        Ok(book)
-    }
-}
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.ref_format_local") {
+            preprocessor.ref_format_local = Some(f.clone());
+        }
 
-/// Finds all patterns `{{numeq}}{mylabel}` (where `{mylabel}` is optional) and replaces them by `\label{mylabel} \tag{ctr}`;
-/// if a label is provided, updates the hashmap `refs` with an entry (label, LabelInfo) allowing to format links to the equation.
-fn find_and_replace_eqs(
-    s: &str,
-    prefix: &str,
-    path: &Path,
-    refs: &mut HashMap<String, LabelInfo>,
-    ctr: &mut usize,
-) -> String {
-    // see https://regex101.com/ for an explanation of the regex
-    let re: Regex = Regex::new(r"\{\{numeq\}\}(\{(?P<label>.*?)\})?").unwrap();
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.ref_format_remote") {
+            preprocessor.ref_format_remote = Some(f.clone());
+        }
 
-    re.replace_all(s, |caps: &regex::Captures| {
-        *ctr += 1;
-        match caps.name("label") {
-            Some(lb) => {
-                // if a label is given, we must update the hashmap
-                let label = lb.as_str().to_string();
-                if refs.contains_key(&label) {
-                    // if the same label has already been used we emit a warning and don't update the hashmap
-                    warn!("Eq. {prefix}{ctr}: Label `{label}' already used");
-                } else {
-                    refs.insert(
-                        label.clone(),
-                        LabelInfo {
-                            num: format!("{prefix}{ctr}"),
-                            path: path.to_path_buf(),
-                        },
-                    );
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.regions") {
+            preprocessor.regions = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.warn_collisions") {
+            preprocessor.warn_collisions = *b;
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.output") {
+            match f.as_str() {
+                "tag" => preprocessor.output = Output::Tag,
+                "data-attr" => preprocessor.output = Output::DataAttr,
+                _ => warn!("Unknown value `{f}' for option output, expected `tag' or `data-attr'"),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.ref_with_caption") {
+            preprocessor.ref_with_caption = *b;
+        }
+
+        if let Some(toml::Value::String(r)) = config.get("preprocessor.numeq.colon_replacement") {
+            preprocessor.colon_replacement = Some(r.clone());
+        }
+
+        if let Some(toml::Value::Table(t)) = config.get("preprocessor.numeq.cref_words") {
+            if let Some(toml::Value::String(w)) = t.get("singular") {
+                preprocessor.cref_singular = w.clone();
+            }
+            if let Some(toml::Value::String(w)) = t.get("plural") {
+                preprocessor.cref_plural = w.clone();
+            }
+        }
+
+        if let Some(toml::Value::String(c)) = config.get("preprocessor.numeq.list_conjunction") {
+            preprocessor.list_conjunction = c.clone();
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.oxford_comma") {
+            preprocessor.oxford_comma = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.warn_self_ref") {
+            preprocessor.warn_self_ref = *b;
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.prefix_source") {
+            match f.as_str() {
+                "section" => preprocessor.prefix_source = PrefixSource::Section,
+                "slug" => preprocessor.prefix_source = PrefixSource::Slug,
+                "index" => preprocessor.prefix_source = PrefixSource::Index,
+                _ => warn!(
+                    "Unknown value `{f}' for option prefix_source, expected `section', `slug' or `index'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.anchor_style") {
+            match f.as_str() {
+                "htmlid" => preprocessor.anchor_style = AnchorStyle::HtmlId,
+                "span" => preprocessor.anchor_style = AnchorStyle::Span,
+                "aname" => preprocessor.anchor_style = AnchorStyle::AName,
+                _ => warn!(
+                    "Unknown value `{f}' for option anchor_style, expected `htmlid', `span' or `aname'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::String(c)) = config.get("preprocessor.numeq.anchor_class") {
+            preprocessor.anchor_class = c.clone();
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.ref_style") {
+            match f.as_str() {
+                "link" => preprocessor.ref_style = RefStyle::Link,
+                "footnote" => preprocessor.ref_style = RefStyle::Footnote,
+                _ => {
+                    warn!("Unknown value `{f}' for option ref_style, expected `link' or `footnote'")
                 }
-                format!("\\htmlId{{{label}}}{{}} \\tag{{{prefix}{ctr}}}")
             }
-            None => {
-                format!("\\tag{{{prefix}{ctr}}}")
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.mathjax_native_refs")
+        {
+            preprocessor.mathjax_native_refs = *b;
+        }
+
+        if let Some(n) = get_usize(&config, "anchor_offset") {
+            preprocessor.anchor_offset = n;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.a11y") {
+            preprocessor.a11y = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.searchable") {
+            preprocessor.searchable = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.prefix_trailing_dot")
+        {
+            preprocessor.prefix_trailing_dot = *b;
+        }
+
+        if let Some(toml::Value::String(sep)) = config.get("preprocessor.numeq.counter_separator") {
+            preprocessor.counter_separator = sep.to_string();
+        }
+
+        if let Some(toml::Value::Array(seps)) = config.get("preprocessor.numeq.prefix_separators") {
+            preprocessor.prefix_separators = seps
+                .iter()
+                .filter_map(|sep| sep.as_str().map(str::to_string))
+                .collect();
+        }
+
+        if let Some(toml::Value::String(sep)) = config.get("preprocessor.numeq.range_separator") {
+            preprocessor.range_separator = sep.to_string();
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.ref_format_range") {
+            preprocessor.ref_format_range = Some(f.clone());
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.require_labels") {
+            preprocessor.require_labels = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.strict") {
+            preprocessor.strict = *b;
+        }
+
+        if let Some(n) = get_usize(&config, "max_label_len") {
+            if n < MIN_LABEL_LEN {
+                warn!("Value `{n}' of option max_label_len is too small, using {MIN_LABEL_LEN} instead");
             }
+            preprocessor.max_label_len = Some(n.max(MIN_LABEL_LEN));
         }
-    })
-    .to_string()
-}
 
-/// Finds and replaces all patterns {{eqref: label}} where label is an existing key in hashmap `refs`
-/// with link towards the relevant theorem.
-fn find_and_replace_refs(
-    s: &str,
-    chap_path: &PathBuf,
-    refs: &HashMap<String, LabelInfo>,
-) -> String {
-    // see https://regex101.com/ for an explanation of the regex
-    let re: Regex = Regex::new(r"\{\{eqref:\s*(?P<label>.*?)\}\}").unwrap();
-
-    re.replace_all(s, |caps: &regex::Captures| {
-        let label = caps.name("label").unwrap().as_str().to_string();
-        if refs.contains_key(&label) {
-            let text = &refs.get(&label).unwrap().num;
-            let path_to_ref = &refs.get(&label).unwrap().path;
-            let rel_path = compute_rel_path(chap_path, path_to_ref);
-            format!("[({text})]({rel_path}#{label})")
-        } else {
-            warn!("Unknown equation reference: {}", label);
-            "**[??]**".to_string()
+        if let Some(toml::Value::Table(external)) = config.get("preprocessor.numeq.external") {
+            for (label, entry) in external {
+                let num = match entry.get("num") {
+                    Some(toml::Value::String(num)) => num.to_string(),
+                    Some(toml::Value::Integer(num)) => num.to_string(),
+                    _ => {
+                        warn!("Ignoring external equation `{label}' without number in option external");
+                        continue;
+                    }
+                };
+                let Some(url) = entry.get("url").and_then(|url| url.as_str()) else {
+                    warn!("Ignoring external equation `{label}' without URL in option external");
+                    continue;
+                };
+                preprocessor.external_refs.insert(
+                    label.to_string(),
+                    LabelInfo {
+                        num,
+                        url: Some(url.to_string()),
+                        ..Default::default()
+                    },
+                );
+            }
         }
-    })
-    .to_string()
-}
 
-/// Computes the relative path from the folder containing `chap_path` to the file `path_to_ref`.
-fn compute_rel_path(chap_path: &PathBuf, path_to_ref: &PathBuf) -> String {
-    if chap_path == path_to_ref {
-        return "".to_string();
-    }
-    let mut local_chap_path = chap_path.clone();
-    local_chap_path.pop();
-    format!(
-        "{}",
-        diff_paths(path_to_ref, &local_chap_path).unwrap().display()
-    )
-}
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.override_external") {
+            preprocessor.override_external = *b;
+        }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use lazy_static::lazy_static;
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.continue_from_import")
+        {
+            preprocessor.continue_from_import = *b;
+        }
 
-    const SECNUM: &str = "1.2.";
+        if let Some(n) = get_usize(&config, "collapse_repeats") {
+            preprocessor.collapse_repeats = n;
+        }
 
-    lazy_static! {
-        static ref PATH: PathBuf = "crypto/groups.md".into();
-    }
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.collapse_ranges") {
+            preprocessor.collapse_ranges = *b;
+        }
 
-    #[test]
-    fn no_label() {
-        let mut refs = HashMap::new();
-        let mut ctr = 0;
-        let input = String::from(r"{{numeq}}");
-        let output = find_and_replace_eqs(&input, SECNUM, &PATH, &mut refs, &mut ctr);
-        let expected = String::from("\\tag{1.2.1}");
-        assert_eq!(output, expected);
-        assert!(refs.is_empty());
-    }
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.dedup_refs") {
+            preprocessor.dedup_refs = *b;
+        }
 
-    #[test]
-    fn with_label() {
-        let mut refs = HashMap::new();
-        let mut ctr = 0;
-        let input = String::from(r"{{numeq}}{eq:test}");
-        let output = find_and_replace_eqs(&input, SECNUM, &PATH, &mut refs, &mut ctr);
-        let expected = String::from("\\htmlId{eq:test}{} \\tag{1.2.1}");
-        assert_eq!(output, expected);
-        assert_eq!(
-            *refs.get("eq:test").unwrap(),
-            LabelInfo {
-                num: "1.2.1".to_string(),
-                path: "crypto/groups.md".into(),
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.rtl") {
+            preprocessor.rtl = *b;
+        }
+
+        if let Some(toml::Value::Table(prefixes)) =
+            config.get("preprocessor.numeq.chapter_prefixes")
+        {
+            for (path, prefix) in prefixes {
+                match prefix.as_str() {
+                    Some(prefix) => {
+                        preprocessor
+                            .chapter_prefixes
+                            .insert(PathBuf::from(path), prefix.to_string());
+                    }
+                    None => {
+                        warn!("Ignoring non-string prefix for `{path}' in option chapter_prefixes")
+                    }
+                }
             }
-        )
+        }
+
+        if let Some(n) = get_usize(&config, "reset_on_heading") {
+            preprocessor.reset_on_heading = Some(n);
+        }
+
+        if let Some(toml::Value::String(p)) = config.get("preprocessor.numeq.fallback_prefix") {
+            preprocessor.fallback_prefix = p.clone();
+        }
+
+        if let Some(toml::Value::Array(streams)) = config.get("preprocessor.numeq.streams") {
+            preprocessor.streams = streams
+                .iter()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect();
+        }
+
+        if let Some(toml::Value::Array(renderers)) =
+            config.get("preprocessor.numeq.strip_renderers")
+        {
+            preprocessor.strip_renderers = renderers
+                .iter()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect();
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.require_math") {
+            preprocessor.require_math = *b;
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.ci") {
+            match f.as_str() {
+                "none" => preprocessor.ci = CiFormat::Plain,
+                "github" => preprocessor.ci = CiFormat::Github,
+                _ => warn!("Unknown value `{f}' for option ci, expected `none' or `github'"),
+            }
+        }
+        preprocessor.src_dir = ctx.config.book.src.clone();
+
+        if let Some(toml::Value::Boolean(b)) =
+            config.get("preprocessor.numeq.show_chapter_on_crosspage")
+        {
+            preprocessor.show_chapter_on_crosspage = *b;
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.number_transform") {
+            match f.as_str() {
+                "none" => preprocessor.number_transform = NumberTransform::None,
+                "thin_space_groups" => preprocessor.number_transform = NumberTransform::ThinSpaceGroups,
+                "upper" => preprocessor.number_transform = NumberTransform::Upper,
+                _ => warn!(
+                    "Unknown value `{f}' for option number_transform, expected `none', `thin_space_groups' or `upper'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.preserve_layout") {
+            preprocessor.preserve_layout = *b;
+        }
+
+        if let Some(toml::Value::String(class)) = config.get("preprocessor.numeq.ref_class") {
+            preprocessor.ref_class = Some(class.to_string());
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.nested_braces") {
+            preprocessor.nested_braces = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.debug_labels") {
+            preprocessor.debug_labels = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.one_per_block") {
+            preprocessor.one_per_block = *b;
+        }
+
+        if let Some(toml::Value::String(p)) = config.get("preprocessor.numeq.label_pattern") {
+            match Regex::new(p) {
+                Ok(re) => preprocessor.label_pattern = Some(re),
+                Err(e) => warn!("Ignoring invalid regex `{p}' for option label_pattern: {e}"),
+            }
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.number_position") {
+            match f.as_str() {
+                "tag" => preprocessor.number_position = NumberPosition::Tag,
+                "above" => preprocessor.number_position = NumberPosition::Above,
+                _ => warn!(
+                    "Unknown value `{f}' for option number_position, expected `tag' or `above'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.bare") {
+            match f.as_str() {
+                "number" => preprocessor.bare = Bare::Number,
+                "ignore" => preprocessor.bare = Bare::Ignore,
+                "remove" => preprocessor.bare = Bare::Remove,
+                _ => warn!(
+                    "Unknown value `{f}' for option bare, expected `number', `ignore' or `remove'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.wrap_block") {
+            preprocessor.wrap_block = *b;
+        }
+
+        if let Some(toml::Value::String(f)) = config.get("preprocessor.numeq.first_component_style")
+        {
+            match f.as_str() {
+                "arabic" => preprocessor.first_component_style = ComponentStyle::Arabic,
+                "roman" => preprocessor.first_component_style = ComponentStyle::Roman,
+                "alpha" => preprocessor.first_component_style = ComponentStyle::Alpha,
+                _ => warn!(
+                    "Unknown value `{f}' for option first_component_style, expected `arabic', `roman' or `alpha'"
+                ),
+            }
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.auto_anchor") {
+            preprocessor.auto_anchor = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.stable_ids") {
+            preprocessor.stable_ids = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.skip_comments") {
+            preprocessor.skip_comments = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.table_inline_numbers")
+        {
+            preprocessor.table_inline_numbers = *b;
+        }
+
+        if let Some(toml::Value::Boolean(b)) = config.get("preprocessor.numeq.skip_manual_tags") {
+            preprocessor.skip_manual_tags = *b;
+        }
+
+        if let Some(toml::Value::String(p)) = config.get("preprocessor.numeq.cache_path") {
+            preprocessor.cache_path = Some(ctx.root.join(p));
+        }
+
+        if let Some(toml::Value::String(p)) = config.get("preprocessor.numeq.backref_map") {
+            preprocessor.backref_map = Some(p.into());
+        }
+
+        preprocessor
+    }
+}
+
+impl Preprocessor for NumEqPreprocessor {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book> {
+        self.run_with_summary(ctx, book).map(|(book, _)| book)
+    }
+}
+
+impl NumEqPreprocessor {
+    /// Seeds the labels with externally defined equations (e.g., from other books), so that references
+    /// to them are resolved. Their paths are relative to the source directory of the book.
+    /// They are added to the external equations of the `external` option, if any.
+    pub fn with_external_refs(mut self, refs: HashMap<String, LabelInfo>) -> Self {
+        self.external_refs.extend(refs);
+        self
+    }
+
+    /// Processes the content of a single chapter at path `path`, numbering its equations with prefix `prefix`,
+    /// and returns the processed content together with the issues found, e.g., for editor integrations.
+    /// Only references to equations of the chapter, to external ones or to those of other chapters
+    /// in the label cache (option `cache_path`) can be resolved.
+    pub fn process_chapter(
+        &self,
+        content: &str,
+        prefix: &str,
+        path: &Path,
+    ) -> (String, Vec<Diagnostic>) {
+        let mut refs = self.external_refs.clone();
+        if let Some(cache_path) = &self.cache_path {
+            // the cached labels of the chapter itself are superseded by the new ones
+            for (label, info) in load_label_map(cache_path) {
+                if info.path != path {
+                    refs.entry(label).or_insert(info);
+                }
+            }
+        }
+        let mut eqs = Vec::new();
+        let mut ctr = Counters::default();
+        let content = &match self.nested_braces {
+            true => self.escape_nested_braces(content),
+            false => content.to_string(),
+        };
+        let content = if self.regions {
+            self.find_and_replace_eqs_in_regions(
+                content, prefix, "", path, &mut refs, &mut ctr, &mut eqs,
+            )
+        } else {
+            self.find_and_replace_eqs(content, prefix, "", path, &mut refs, &mut ctr, &mut eqs)
+        };
+        let mut diagnostics = Vec::new();
+        for eq in &eqs {
+            if let Some(label) = &eq.duplicate {
+                diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::DuplicateLabel,
+                    label: Some(label.clone()),
+                    message: format!("Eq. {}: Label `{label}' already used", eq.num),
+                });
+            } else if eq.label.is_none() && self.require_labels {
+                diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::MissingLabel,
+                    label: None,
+                    message: format!("Eq. {}: Missing label", eq.num),
+                });
+            }
+        }
+        let mut summary = NumEqSummary::default();
+        let content = self.find_and_replace_refs(&content, "", path, &refs, &eqs, &mut summary);
+        for (label, _) in summary.unresolved {
+            diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::UnresolvedReference,
+                message: format!(
+                    "Unknown equation reference: {label} (equations of other chapters are not known when processing a single chapter)"
+                ),
+                label: Some(label),
+            });
+        }
+        (content, diagnostics)
+    }
+
+    /// Processes the book like `Preprocessor::run`, additionally returning a summary of the run.
+    pub fn run_with_summary(
+        &self,
+        ctx: &PreprocessorContext,
+        mut book: Book,
+    ) -> Result<(Book, NumEqSummary)> {
+        if self.strip_renderers.contains(&ctx.renderer) {
+            // renderers which do not support numbers and links (e.g., KaTeX) get clean content
+            debug!("Stripping markers for the {} renderer", ctx.renderer);
+            book.for_each_mut(|item| {
+                if let BookItem::Chapter(chapter) = item {
+                    chapter.content = self.strip_markers(&chapter.content);
+                }
+            });
+            return Ok((book, NumEqSummary::default()));
+        }
+        // a hashmap mapping labels to `LabelInfo` structs
+        let mut refs: HashMap<String, LabelInfo> = self.external_refs.clone();
+        // equation counter
+        let mut ctr = Counters::default();
+        // store current (sub-)chapter number according to the depth
+        // initialize with one 1 followed by (prefix_depth - 1) zeros
+        let mut ccn: Vec<usize> = vec![1];
+        ccn.resize(self.prefix_depth, 0);
+        // statistics about equations and references
+        let mut summary = NumEqSummary::default();
+        // the policy violations making the build fail in strict mode
+        let mut errors: Vec<String> = Vec::new();
+
+        // chapters with the same path would make links (and ordinal references) ambiguous
+        let mut paths: HashMap<&Path, &str> = HashMap::new();
+        for item in book.iter() {
+            if let BookItem::Chapter(chapter) = item {
+                if let Some(path) = &chapter.path {
+                    if let Some(first) = paths.insert(path, &chapter.name) {
+                        let msg = format!(
+                            "{}: Chapter `{first}' has the same path, references to the equations of both chapters are ambiguous",
+                            self.location(&chapter.name, path)
+                        );
+                        if self.strict {
+                            errors.push(msg);
+                        } else {
+                            warn!("{msg}");
+                        }
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(self.failure(&errors));
+        }
+        // the paths of the chapters of the book, whose cached labels are still valid
+        let book_paths: HashSet<PathBuf> = paths.keys().map(|path| path.to_path_buf()).collect();
+        // a hashmap mapping equation numbers to the path of the chapter where they first appeared
+        let mut numbers: HashMap<String, PathBuf> = HashMap::new();
+        // a hashmap mapping chapter paths to the equations they contain, in order
+        let mut chapter_eqs: HashMap<PathBuf, Vec<Equation>> = HashMap::new();
+        // the names of the current group chapter and its ancestors, the prefix of the group
+        // and its counters after its last chapter (the global counter being unused)
+        let mut group: Option<(Vec<String>, String, Counters)> = None;
+        // the slugs already used as prefixes
+        let mut slugs: HashSet<String> = HashSet::new();
+        // whether a new part of the book has started since the last chapter
+        let mut new_part = false;
+        // whether a numbered chapter has been seen, and the number of appendices seen
+        let mut seen_numbered = false;
+        let mut appendices = 0;
+        // the number of chapters seen so far
+        let mut index = 0;
+        // a hashmap mapping prefixes to the highest counter of external equations with this prefix
+        let mut imported: HashMap<String, usize> = HashMap::new();
+        if self.continue_from_import {
+            // equations outside of any book (with a URL) do not share the numbering
+            for info in self
+                .external_refs
+                .values()
+                .filter(|info| info.url.is_none())
+            {
+                if let Some((prefix, n)) = split_number(&info.num) {
+                    let max = imported.entry(prefix.to_string()).or_insert(0);
+                    *max = (*max).max(n);
+                }
+            }
+        }
+
+        let start = Instant::now();
+
+        for_each_mut_ordered(
+            &mut |item: &mut BookItem| {
+                if let BookItem::PartTitle(_) = item {
+                    new_part = true;
+                }
+                if let BookItem::Chapter(chapter) = item {
+                    if !chapter.is_draft_chapter() {
+                        // one can safely unwrap chapter.path which must be Some(...)
+                        let mut prefix = if self.with_prefix {
+                            match &chapter.number {
+                                Some(sn) => sn.to_string(),
+                                None => self.fallback_prefix.clone(),
+                            }
+                        } else {
+                            String::new()
+                        };
+                        let path = chapter.path.as_ref().unwrap();
+                        let loc = self.location(&chapter.name, path);
+                        let directives = take_directives(&mut chapter.content, &loc);
+                        if self.nested_braces {
+                            // nested braces are escaped once and for all, for both passes
+                            chapter.content = self.escape_nested_braces(&chapter.content);
+                        }
+                        let grouped = matches!(&group, Some((parents, _, _)) if *parents == chapter.parent_names);
+                        // unnumbered chapters after numbered ones are appendices (suffix chapters)
+                        let appendix = self.appendices && chapter.number.is_none() && seen_numbered;
+                        seen_numbered |= chapter.number.is_some();
+                        index += 1;
+                        if grouped {
+                            // direct sub-chapters of a group continue the numbering of their parent,
+                            // whatever the numbering of the sub-chapters in between
+                            let (_, group_prefix, saved) = group.as_ref().unwrap();
+                            prefix = group_prefix.clone();
+                            ctr.main = saved.main;
+                            ctr.streams = saved.streams.clone();
+                        } else if self.with_prefix && self.prefix_source == PrefixSource::Slug {
+                            // slug prefixes are specific to each chapter, so the counter is always reset
+                            ctr.reset();
+                            let slug = slugify(&chapter.name);
+                            // repeated slugs get a count, which may itself collide with other slugs
+                            let mut unique = slug.clone();
+                            let mut count = 1;
+                            while slugs.contains(&unique) {
+                                count += 1;
+                                unique = format!("{slug}-{count}");
+                            }
+                            prefix = format!("{unique}:");
+                            slugs.insert(unique);
+                        } else if self.with_prefix && self.prefix_source == PrefixSource::Index {
+                            // like slug prefixes, index prefixes are specific to each chapter
+                            ctr.reset();
+                            prefix = format!("{index}.");
+                        } else if appendix {
+                            // each appendix has its own counter, with a letter prefix
+                            ctr.reset();
+                            appendices += 1;
+                            if self.with_prefix {
+                                prefix = format!("{}.", appendix_letter(appendices));
+                            }
+                        } else {
+                            // obtain the chapter number as vector of usize
+                            // (a fallback prefix is not a section number)
+                            let parsed = if prefix.is_empty() || chapter.number.is_none() {
+                                None
+                            } else {
+                                parse_section_number(&prefix, &loc)
+                            };
+                            // if prefix is empty (or invalid), there is no key
+                            // (the components of the prefix whose change resets the counter with depth reset)
+                            let key = match (self.reset_depth, &parsed) {
+                                (_, None) => None,
+                                (None, Some(_)) if self.prefix_depth == 0 => None,
+                                // with bottom slicing, each (sub-)chapter has its own counter
+                                (None, Some(number)) if self.depth_from_bottom => {
+                                    let mut key = number.clone();
+                                    if key.len() < self.prefix_depth {
+                                        key.resize(self.prefix_depth, 0);
+                                    }
+                                    Some(key)
+                                }
+                                // with top slicing, deeper sub-chapters share the counter of their ancestor
+                                (depth, Some(number)) => {
+                                    let mut key = number.clone();
+                                    key.resize(depth.unwrap_or(self.prefix_depth), 0);
+                                    Some(key)
+                                }
+                            };
+                            let styled = self.first_component_style != ComponentStyle::Arabic
+                                || !self.prefix_separators.is_empty();
+                            if let (true, Some(mut prefix_vec)) =
+                                (self.prefix_depth > 0 || styled, parsed)
+                            {
+                                // with depth 0, all components are displayed
+                                let depth = match self.prefix_depth {
+                                    0 => prefix_vec.len(),
+                                    d => d,
+                                };
+                                if prefix_vec.len() < depth {
+                                    prefix_vec.resize(depth, 0);
+                                }
+                                // the displayed components
+                                let shown = if self.depth_from_bottom {
+                                    &prefix_vec[prefix_vec.len() - depth..]
+                                } else {
+                                    &prefix_vec[..depth]
+                                };
+                                // update prefix
+                                prefix =
+                                    shown.iter().enumerate().fold(String::new(), |acc, (i, x)| {
+                                        let style = match i {
+                                            0 => self.first_component_style,
+                                            _ => ComponentStyle::Arabic,
+                                        };
+                                        // the last dot is the one before the counter
+                                        let sep = match self.prefix_separators.get(i) {
+                                            _ if i + 1 == shown.len() => ".",
+                                            Some(sep) => sep,
+                                            None => {
+                                                self.prefix_separators.last().map_or(".", |s| s)
+                                            }
+                                        };
+                                        acc + &format_component(*x, style) + sep
+                                    });
+                            }
+                            match self.reset {
+                                Reset::Never => (),
+                                Reset::Chapter => ctr.reset(),
+                                Reset::Part => {
+                                    if new_part {
+                                        ctr.reset();
+                                    }
+                                }
+                                Reset::Depth => match key {
+                                    // if ccn is different from key, update ccn and reset counter
+                                    Some(key) if key == ccn => (),
+                                    Some(key) => {
+                                        ccn = key;
+                                        ctr.reset();
+                                    }
+                                    None => {
+                                        // the next numbered chapter starts a new counter as well
+                                        ccn.clear();
+                                        ctr.reset();
+                                    }
+                                },
+                            }
+                        }
+                        new_part = false;
+                        if let Some(code) = self.chapter_prefixes.get(path) {
+                            // a configured prefix overrides the computed one, with its own counter
+                            prefix = format!("{code}.");
+                            ctr.reset();
+                        }
+                        if let Some(code) =
+                            directives.iter().rev().find_map(|d| d.strip_prefix("tag="))
+                        {
+                            // a chapter code overrides the computed prefix, with its own counter
+                            prefix = format!("{code}.");
+                            ctr.reset();
+                        }
+                        let starts_group = directives.iter().any(|d| d == "group");
+                        if starts_group {
+                            let mut parents = chapter.parent_names.clone();
+                            parents.push(chapter.name.clone());
+                            group = Some((parents, prefix.clone(), Counters::default()));
+                        }
+                        if self.warn_self_ref {
+                            for label in self.find_self_refs(&chapter.content) {
+                                warn!(
+                                    "{loc}: Equation `{label}' is referenced within its own block"
+                                );
+                            }
+                        }
+                        // exactly one separator between the prefix and the counter, even if the prefix
+                        // already ends with one (e.g., a chapter code such as `A.`)
+                        if prefix.ends_with('.') {
+                            prefix = format!("{}.", prefix.trim_end_matches('.'));
+                        }
+                        if let Some(head) = prefix.strip_suffix('.') {
+                            // without trailing dot, the counter is parenthesized (see `compose_number`)
+                            let sep = match self.prefix_trailing_dot {
+                                true => self.counter_separator.as_str(),
+                                false => "(",
+                            };
+                            prefix = format!("{head}{sep}");
+                        }
+                        if let Some(max) = imported.get(&prefix) {
+                            ctr.main = ctr.main.max(*max);
+                        }
+                        if let (Some(max), true) = (self.max_label_len, self.strict) {
+                            for caps in EQ_MARKER.captures_iter(&chapter.content) {
+                                if let Some(lb) = self.marker_label(&caps) {
+                                    if lb.chars().count() > max {
+                                        let pos = line_col(
+                                            &chapter.content,
+                                            caps.get(0).unwrap().start(),
+                                        );
+                                        errors.push(format!(
+                                            "{}: Label `{lb}' is longer than {max} characters",
+                                            self.location_at(&chapter.name, path, Some(pos))
+                                        ));
+                                    }
+                                }
+                            }
+                        }
+                        if self.require_math {
+                            let blocks = find_math_blocks(&chapter.content);
+                            let processed = self.processed_markers(&chapter.content);
+                            for caps in EQ_MARKER.captures_iter(&chapter.content) {
+                                let marker = caps.get(0).unwrap();
+                                let start = marker.start();
+                                // closing a family does not number an equation
+                                let control = caps
+                                    .name("arg")
+                                    .is_some_and(|arg| arg.as_str().trim() == "endfamily");
+                                // neither do bare markers which are ignored or removed
+                                let bare = caps.name("arg").is_none()
+                                    && caps.name("label").map_or("", |lb| lb.as_str()).is_empty()
+                                    && self.bare != Bare::Number;
+                                if !control
+                                    && !bare
+                                    && processed(start)
+                                    && !blocks.iter().any(|r| r.contains(&start))
+                                {
+                                    let (line, column) = line_col(&chapter.content, start);
+                                    errors.push(format!(
+                                        "{}: Marker `{}' outside of a math block (line {line}, column {column})",
+                                        self.location_at(&chapter.name, path, Some((line, column))),
+                                        marker.as_str()
+                                    ));
+                                }
+                            }
+                        }
+                        let ctr_before = ctr.main;
+                        let mut eqs = Vec::new();
+                        chapter.content = if self.regions {
+                            self.find_and_replace_eqs_in_regions(
+                                &chapter.content,
+                                &prefix,
+                                &chapter.name,
+                                path,
+                                &mut refs,
+                                &mut ctr,
+                                &mut eqs,
+                            )
+                        } else {
+                            self.find_and_replace_eqs(
+                                &chapter.content,
+                                &prefix,
+                                &chapter.name,
+                                path,
+                                &mut refs,
+                                &mut ctr,
+                                &mut eqs,
+                            )
+                        };
+                        if let (Some((_, _, saved)), true) = (&mut group, grouped || starts_group) {
+                            saved.main = ctr.main;
+                            saved.streams = ctr.streams.clone();
+                        }
+                        let section = chapter
+                            .number
+                            .as_ref()
+                            .map(|sn| sn.to_string().trim_end_matches('.').to_string());
+                        let title = strip_markdown(&chapter.name);
+                        for label in eqs.iter().filter_map(|eq| eq.label.as_ref()) {
+                            let info = refs.get_mut(label).unwrap();
+                            info.section = section.clone();
+                            info.title = Some(title.clone());
+                        }
+                        summary.total += eqs.len();
+                        for eq in &eqs {
+                            if eq.label.is_some() {
+                                summary.labeled += 1;
+                            }
+                            if let (Some(re), Some(label)) = (
+                                &self.label_pattern,
+                                eq.label.as_ref().or(eq.duplicate.as_ref()),
+                            ) {
+                                if !re.is_match(label) {
+                                    let msg = format!(
+                                        "{}: Eq. {}: Label `{label}' does not match pattern `{re}'",
+                                        self.location_at(&chapter.name, path, Some(eq.pos)),
+                                        eq.num
+                                    );
+                                    if self.strict {
+                                        errors.push(msg);
+                                    } else {
+                                        warn!("{msg}");
+                                    }
+                                }
+                            }
+                            if let Some(label) = &eq.duplicate {
+                                summary.duplicates.push((label.clone(), path.clone()));
+                            } else if eq.label.is_none() && self.require_labels {
+                                let msg = format!(
+                                    "{}: Eq. {}: Missing label",
+                                    self.location_at(&chapter.name, path, Some(eq.pos)),
+                                    eq.num
+                                );
+                                if self.strict {
+                                    errors.push(msg);
+                                } else {
+                                    warn!("{msg}");
+                                }
+                            }
+                        }
+                        chapter_eqs.insert(path.clone(), eqs);
+                        if self.warn_collisions {
+                            for n in ctr_before.saturating_add(1)..=ctr.main {
+                                let num = compose_number(&prefix, n);
+                                match numbers.get(&num) {
+                                    Some(first) if first != path => warn!(
+                                        "{loc}: Eq. {num}: Number already used in {}, references by number would be ambiguous",
+                                        first.display()
+                                    ),
+                                    Some(_) => (),
+                                    None => {
+                                        numbers.insert(num, path.clone());
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            &mut book.sections,
+        );
+        debug!(
+            "Numbered {} equations in {:?} (labels defined: {})",
+            summary.total,
+            start.elapsed(),
+            refs.len()
+        );
+        if !errors.is_empty() {
+            return Err(self.failure(&errors));
+        }
+
+        if let Some(cache_path) = &self.cache_path {
+            // cached labels of chapters processed in this run are superseded by the new ones,
+            // and those of chapters removed from the book are dropped
+            for (label, info) in load_label_map(cache_path) {
+                if book_paths.contains(&info.path)
+                    && !chapter_eqs.contains_key(&info.path)
+                    && !refs.contains_key(&label)
+                {
+                    refs.insert(label, info);
+                }
+            }
+            save_json(cache_path, &refs, "label cache");
+        }
+
+        let start = Instant::now();
+        book.for_each_mut(|item: &mut BookItem| {
+            if let BookItem::Chapter(chapter) = item {
+                if !chapter.is_draft_chapter() {
+                    // one can safely unwrap chapter.path which must be Some(...)
+                    let path = chapter.path.as_ref().unwrap();
+                    let eqs = chapter_eqs.get(path).map_or(&[][..], |eqs| &eqs[..]);
+                    if ESCAPED_MARKER.is_match(&chapter.content) {
+                        warn!(
+                            "{}: Found escaped equation markers or references, which are not processed (another preprocessor may have to run after numeq)",
+                            self.location(&chapter.name, path)
+                        );
+                    }
+                    chapter.content = self.find_and_replace_refs(
+                        &chapter.content,
+                        &chapter.name,
+                        path,
+                        &refs,
+                        eqs,
+                        &mut summary,
+                    );
+                }
+            }
+        });
+        debug!(
+            "Processed {} equation references in {:?}",
+            summary.references,
+            start.elapsed()
+        );
+
+        if let Some(backref_map) = &self.backref_map {
+            save_json(
+                &ctx.root.join(backref_map),
+                &summary.backrefs,
+                "back-reference map",
+            );
+        }
+
+        summary.labels = refs;
+        Ok((book, summary))
+    }
+
+    /// Finds all patterns `{{numeq}}{mylabel}{mycaption}` (where `{mylabel}` and `{mycaption}` are optional) and replaces them by `\label{mylabel} \tag{ctr}`;
+    /// if a label is provided, updates the hashmap `refs` with an entry (label, LabelInfo) allowing to format links to the equation.
+    /// Only the marker itself is replaced, surrounding whitespace and newlines being kept, except that
+    /// markers removed from the output take their line with them if they are alone on it (unless `preserve_layout` is set).
+    /// Each numbered equation is appended to `eqs`.
+    ///
+    /// With the `data-attr` output, the marker is removed and the enclosing math block is wrapped
+    /// with a `<div>` element carrying the number as a `data-eqnum` attribute (and the label as id).
+    /// With `wrap_block`, the enclosing math block is similarly wrapped with a `<div class="equation">` element
+    /// carrying the label as id, the number being still displayed with `\tag`.
+    ///
+    /// If `s` contains both markers and manual `\tag` commands, it is left untouched when `skip_manual_tags` is set,
+    /// and numbered with a warning otherwise.
+    #[allow(clippy::too_many_arguments)]
+    fn find_and_replace_eqs(
+        &self,
+        s: &str,
+        prefix: &str,
+        chap_name: &str,
+        path: &Path,
+        refs: &mut HashMap<String, LabelInfo>,
+        ctr: &mut Counters,
+        eqs: &mut Vec<Equation>,
+    ) -> String {
+        self.find_and_replace_eqs_at(s, (1, 1), prefix, chap_name, path, refs, ctr, eqs)
+    }
+
+    /// Like `find_and_replace_eqs`, where `s` starts at position `start` (line and column) of the chapter,
+    /// which is used for the positions of warnings.
+    #[allow(clippy::too_many_arguments)]
+    fn find_and_replace_eqs_at(
+        &self,
+        s: &str,
+        start: (usize, usize),
+        prefix: &str,
+        chap_name: &str,
+        path: &Path,
+        refs: &mut HashMap<String, LabelInfo>,
+        ctr: &mut Counters,
+        eqs: &mut Vec<Equation>,
+    ) -> String {
+        // content without markers (e.g., already processed content) is left untouched
+        if !EQ_MARKER.is_match(s) {
+            return s.to_string();
+        }
+        let loc = self.location(chap_name, path);
+        if MANUAL_TAG.is_match(s) {
+            if self.skip_manual_tags {
+                debug!("{loc}: Skipping numbering of content with manual \\tag commands");
+                return s.to_string();
+            }
+            warn!(
+                "{loc}: Both {{{{numeq}}}} markers and manual \\tag commands found, numbers may be inconsistent"
+            );
+        }
+        let blocks = find_math_blocks(s);
+        let comments = if self.skip_comments {
+            find_comments(s)
+        } else {
+            Vec::new()
+        };
+        let headings = match self.reset_on_heading {
+            Some(level) => find_headings(s, level),
+            None => Vec::new(),
+        };
+        // the number of headings before the last numbered equation
+        let mut section = 0;
+        // the index of the last wrapped math block
+        let mut wrapped: Option<usize> = None;
+        // the index of the math block of the last numbered equation
+        let mut numbered: Option<usize> = None;
+        let mut edits = Vec::new();
+        // the active family: its name, its number and the number of its members so far
+        let mut family: Option<(String, String, usize)> = None;
+        // the position in the chapter of the previous marker and its offset in `s`
+        let (mut pos, mut offset) = (start, 0);
+
+        for caps in EQ_MARKER.captures_iter(s) {
+            let marker = caps.get(0).unwrap();
+            pos = shift_pos(pos, line_col(&s[offset..], marker.start() - offset));
+            offset = marker.start();
+            let loc = self.location_at(chap_name, path, Some(pos));
+            if comments.iter().any(|c| c.contains(&marker.start())) {
+                // draft equations in comments are neither counted nor replaced
+                continue;
+            }
+            let headings_before = headings.partition_point(|&h| h < marker.start());
+            if headings_before != section {
+                // a heading of the configured level starts a new section
+                section = headings_before;
+                ctr.reset();
+                family = None;
+            }
+            let block = blocks.iter().position(|b| b.contains(&marker.start()));
+            let mut family_name = None;
+            let mut stream = None;
+            let mut start = None;
+            match caps.name("arg").map(|arg| arg.as_str().trim()) {
+                None => (),
+                Some("endfamily") => {
+                    if family.take().is_none() {
+                        warn!("{loc}: Ignoring `{{{{numeq:endfamily}}}}' without open family");
+                    }
+                    edits.push((marker.range(), String::new()));
+                    continue;
+                }
+                Some(arg) if self.streams.iter().any(|name| name == arg) => stream = Some(arg),
+                // known arguments take precedence over the colon syntax for labels
+                Some(arg) => match arg.split_once('=').map(|(k, v)| (k.trim_end(), v.trim())) {
+                    Some(("family", name)) => family_name = Some(name),
+                    Some(("start", n)) => match n.parse::<usize>() {
+                        Ok(n) if n > 0 => start = Some(n),
+                        _ => warn!(
+                            "{loc}: Invalid start number `{n}', numbering the equation as usual"
+                        ),
+                    },
+                    // a label given with the colon syntax, see `marker_label`
+                    _ if caps.name("label").is_none() => (),
+                    _ => warn!(
+                        "{loc}: Unknown marker argument `{arg}', numbering the equation as usual"
+                    ),
+                },
+            }
+            let bare = caps.name("arg").is_none()
+                && caps.name("label").map_or("", |lb| lb.as_str()).is_empty();
+            match (bare, self.bare) {
+                (true, Bare::Ignore) => continue,
+                (true, Bare::Remove) => {
+                    edits.push((marker.range(), String::new()));
+                    continue;
+                }
+                _ => (),
+            }
+            if self.output == Output::DataAttr && block.is_some() && block == wrapped {
+                warn!(
+                    "{loc}: Eq. {}: Only one number per math block can be displayed with the data-attr output, ignoring marker `{}'",
+                    compose_number(prefix, ctr.main),
+                    marker.as_str()
+                );
+                edits.push((marker.range(), String::new()));
+                continue;
+            }
+            if self.one_per_block && block.is_some() && block == numbered {
+                warn!(
+                    "{loc}: Only the first marker of a math block is numbered with option one_per_block, ignoring marker `{}'",
+                    marker.as_str()
+                );
+                edits.push((marker.range(), String::new()));
+                continue;
+            }
+            numbered = block;
+            let num = match (&mut family, family_name) {
+                // a new member of the active family
+                (Some((name, parent, members)), Some(f)) if name == f => {
+                    *members += 1;
+                    format!("{parent}{}", appendix_letter(*members).to_lowercase())
+                }
+                _ => {
+                    let ctr = match stream {
+                        Some(name) => ctr.streams.entry(name.to_string()).or_insert(0),
+                        None => &mut ctr.main,
+                    };
+                    if let Some(n) = start {
+                        // the equation is numbered `n` and the following ones continue from it
+                        *ctr = n - 1;
+                    }
+                    if *ctr == usize::MAX {
+                        warn!(
+                            "{loc}: Eq. {}: Equation counter overflow, number not incremented",
+                            compose_number(prefix, *ctr)
+                        );
+                    }
+                    *ctr = ctr.saturating_add(1);
+                    let num = compose_number(prefix, *ctr);
+                    match family_name {
+                        // the first member of a new family (closing the previous one, if any)
+                        Some(f) => {
+                            family = Some((f.to_string(), num.clone(), 1));
+                            format!("{num}a")
+                        }
+                        None => num,
+                    }
+                }
+            };
+            // the displayed and stored number, so that references are consistent with it
+            let num = transform_number(&num, self.number_transform);
+            ctr.global += 1;
+            // the anchor id of the equation with stable ids
+            let stable_id = self.stable_ids.then(|| format!("eq-{}", ctr.global));
+            let mut label = self.marker_label(&caps).map(|lb| self.shorten_label(lb));
+            if label.as_deref() == Some("") {
+                // an empty label is treated as no label
+                warn!("{loc}: Eq. {num}: Empty label `{{}}' ignored");
+                label = None;
+            }
+            // whether the label has been registered in the hashmap
+            let mut registered = false;
+            if let Some(lb) = &label {
+                // if a label is given, we must update the hashmap
+                // an external label not redefined yet
+                let external = self.override_external && refs.get(lb) == self.external_refs.get(lb);
+                if let (Some(first), false) = (refs.get(lb), external) {
+                    // if the same label has already been used we emit a warning and don't update the hashmap
+                    match &first.url {
+                        Some(url) => warn!(
+                            "{loc}: Eq. {num}: Label `{lb}' already used for external Eq. {} at {url}",
+                            first.num
+                        ),
+                        None => warn!(
+                            "{loc}: Eq. {num}: Label `{lb}' already used for Eq. {} in {}",
+                            first.num,
+                            first.path.display()
+                        ),
+                    }
+                } else {
+                    refs.insert(
+                        lb.clone(),
+                        LabelInfo {
+                            num: num.clone(),
+                            path: path.to_path_buf(),
+                            caption: caps.name("caption").map(|c| unescape(c.as_str())),
+                            section: None,
+                            title: None,
+                            meta: caps
+                                .name("meta")
+                                .map_or(HashMap::new(), |m| parse_meta(m.as_str(), &num, &loc)),
+                            id: stable_id.clone(),
+                            url: None,
+                        },
+                    );
+                    registered = true;
+                }
+            }
+            eqs.push(Equation {
+                pos,
+                num: num.clone(),
+                label: label.clone().filter(|_| registered),
+                duplicate: label.clone().filter(|_| !registered),
+            });
+            // a synthetic label derived from the number of an unlabeled equation
+            let auto = match (&label, self.auto_anchor) {
+                (None, true) => {
+                    let key = auto_anchor_key(&num);
+                    if refs.contains_key(&key) {
+                        warn!(
+                            "{loc}: Eq. {num}: Anchor id `{key}' already used, no anchor emitted"
+                        );
+                        None
+                    } else {
+                        refs.insert(
+                            key.clone(),
+                            LabelInfo {
+                                num: num.clone(),
+                                path: path.to_path_buf(),
+                                id: stable_id.clone(),
+                                ..Default::default()
+                            },
+                        );
+                        Some(key)
+                    }
+                }
+                _ => None,
+            };
+            let id = label
+                .as_deref()
+                .or(auto.as_deref())
+                .map(|lb| stable_id.unwrap_or_else(|| self.anchor_id(lb)));
+            // the content of `\tag`, which only shows the label in debug mode
+            let tag = match (&label, self.debug_labels) {
+                (Some(lb), true) => format!("{num} {}", escape_tex(lb)),
+                _ => num.clone(),
+            };
+            match (self.output, block) {
+                (Output::DataAttr, Some(i)) => {
+                    wrapped = block;
+                    let id = id.map_or(String::new(), |id| format!(" id=\"{id}\""));
+                    edits.push((
+                        blocks[i].start..blocks[i].start,
+                        format!("<div class=\"numeq\" data-eqnum=\"{num}\"{id}>\n\n"),
+                    ));
+                    edits.push((marker.range(), String::new()));
+                    edits.push((blocks[i].end..blocks[i].end, "\n\n</div>".to_string()));
+                }
+                (Output::Tag, Some(i))
+                    if self.number_position == NumberPosition::Above
+                        && !(self.table_inline_numbers && in_table_row(s, marker.start())) =>
+                {
+                    // the number is displayed in bold on its own line above the math block, with the anchor
+                    let anchor =
+                        id.map_or(String::new(), |id| format!("<span id=\"{id}\"></span>"));
+                    let start = blocks[i].start;
+                    let bold = self.isolate(format!("**({num})**"));
+                    let number = if start == 0 || s[..start].ends_with('\n') {
+                        format!("{anchor}{bold}\n\n")
+                    } else {
+                        // the block follows other content on its line (e.g., an opening HTML tag) which a blank
+                        // line would separate from it, so the number is written in HTML instead
+                        let strong = self.isolate(format!("<strong>({num})</strong>"));
+                        format!("{anchor}{strong}<br>")
+                    };
+                    edits.push((start..start, number));
+                    edits.push((marker.range(), String::new()));
+                }
+                (Output::Tag, Some(i))
+                    if self.wrap_block
+                        && block != wrapped
+                        && !(self.table_inline_numbers && in_table_row(s, marker.start())) =>
+                {
+                    // the anchor is carried by the container (further markers of the block get a usual anchor)
+                    wrapped = block;
+                    let id = id.map_or(String::new(), |id| format!(" id=\"{id}\""));
+                    edits.push((
+                        blocks[i].start..blocks[i].start,
+                        format!("<div class=\"equation\"{id}>\n\n"),
+                    ));
+                    edits.push((marker.range(), format!("\\tag{{{tag}}}")));
+                    edits.push((blocks[i].end..blocks[i].end, "\n\n</div>".to_string()));
+                }
+                _ => {
+                    if self.output == Output::DataAttr {
+                        warn!(
+                            "{loc}: Eq. {num}: Marker outside of a math block (`$$ ... $$' or `\\[ ... \\]'), falling back to \\tag"
+                        );
+                    }
+                    let mut replacement =
+                        if self.table_inline_numbers && in_table_row(s, marker.start()) {
+                            // `\tag` may break the layout of tables, so the number is displayed as text
+                            match block {
+                                Some(i) => {
+                                    edits.push((blocks[i].end..blocks[i].end, format!(" ({num})")));
+                                    String::new()
+                                }
+                                None => format!("({num})"),
+                            }
+                        } else {
+                            format!("\\tag{{{tag}}}")
+                        };
+                    if let (true, Some(lb)) = (self.mathjax_native_refs, &label) {
+                        // MathJax handles the anchor and references within the chapter
+                        replacement = format!("\\label{{{lb}}} {replacement}")
+                            .trim_end()
+                            .to_string();
+                    } else if let Some(id) = id {
+                        let class = if self.anchor_class.is_empty() {
+                            String::new()
+                        } else {
+                            format!(" class=\"{}\"", self.anchor_class)
+                        };
+                        match (self.anchor_style, block) {
+                            // an anchor shifted upwards, so that the equation is not at the top edge when scrolled to
+                            (_, Some(i)) if self.anchor_offset > 0 => edits.push((
+                                blocks[i].start..blocks[i].start,
+                                format!(
+                                    "<span id=\"{id}\" style=\"display: block; position: relative; top: -{}em\"></span>",
+                                    self.anchor_offset
+                                ),
+                            )),
+                            (AnchorStyle::Span, Some(i)) => edits.push((
+                                blocks[i].start..blocks[i].start,
+                                format!("<span id=\"{id}\"{class}></span>"),
+                            )),
+                            (AnchorStyle::AName, Some(i)) => edits.push((
+                                blocks[i].start..blocks[i].start,
+                                format!("<a name=\"{id}\"{class}></a>"),
+                            )),
+                            (style, _) => {
+                                if style != AnchorStyle::HtmlId {
+                                    warn!("{loc}: Eq. {num}: Marker outside of a math block (`$$ ... $$' or `\\[ ... \\]'), falling back to \\htmlId anchor");
+                                }
+                                replacement = format!("\\htmlId{{{id}}}{{}} {replacement}")
+                                    .trim_end()
+                                    .to_string();
+                            }
+                        }
+                    }
+                    edits.push((marker.range(), replacement));
+                }
+            }
+            if let (true, Some(i)) = (self.a11y, block) {
+                // visually hidden text announcing the number to screen readers (after the containers opened above)
+                edits.push((
+                    blocks[i].start..blocks[i].start,
+                    format!(
+                        "<span class=\"sr-only\">{} {num}</span>",
+                        self.cref_singular
+                    ),
+                ));
+            }
+            if let (true, Some(i)) = (self.searchable, block) {
+                // hidden text indexed by the search of mdBook
+                edits.push((
+                    blocks[i].start..blocks[i].start,
+                    format!("<span hidden>{} {num}</span>", self.cref_singular),
+                ));
+            }
+        }
+
+        if !self.preserve_layout {
+            // removed markers alone on their line take the line with them, leaving no stray blank line
+            for (range, replacement) in &mut edits {
+                if replacement.is_empty() {
+                    *range = line_of(s, range.clone());
+                }
+            }
+        }
+        apply_edits(s, edits)
+    }
+
+    /// Finds all regions `{{numeq-begin}} ... {{numeq-end}}` and applies `find_and_replace_eqs` to their content,
+    /// removing the region delimiters; markers outside of regions are left untouched.
+    #[allow(clippy::too_many_arguments)]
+    fn find_and_replace_eqs_in_regions(
+        &self,
+        s: &str,
+        prefix: &str,
+        chap_name: &str,
+        path: &Path,
+        refs: &mut HashMap<String, LabelInfo>,
+        ctr: &mut Counters,
+        eqs: &mut Vec<Equation>,
+    ) -> String {
+        let output = NUMBERING_REGION
+            .replace_all(s, |caps: &regex::Captures| {
+                let region = caps.name("region").unwrap();
+                let start = line_col(s, region.start());
+                self.find_and_replace_eqs_at(
+                    region.as_str(),
+                    start,
+                    prefix,
+                    chap_name,
+                    path,
+                    refs,
+                    ctr,
+                    eqs,
+                )
+            })
+            .to_string();
+        if output.contains("{{numeq-begin}}") {
+            warn!(
+                "{}: Unterminated `{{{{numeq-begin}}}}' region",
+                self.location(chap_name, path)
+            );
+        }
+        output
+    }
+
+    /// Finds and replaces all patterns {{eqref: label}} where label is an existing key in hashmap `refs`
+    /// with link towards the relevant theorem; each reference found (and each unresolved label) is recorded in `summary`.
+    /// A comma-separated list of labels {{eqref: label1, label2}} is replaced by the list of links.
+    /// If `label` is not a known label but a positive integer n, the reference points to the n-th equation
+    /// of the current chapter, whose equations are `eqs`.
+    ///
+    /// Also replaces all patterns {{cref: label1, label2, ...}} with the list of links preceded by the word
+    /// "Equation" or "Equations" (e.g., "Equations (1) and (2)").
+    ///
+    /// An item `line=n` annotates the previous item with ", line n" in the link text.
+    ///
+    /// An item `label1..label2` of a list is a range, whose endpoints are joined with the range separator
+    /// (unless `label1..label2` is itself a known label).
+    ///
+    /// With the footnote reference style, links are replaced by footnote references
+    /// and the corresponding footnote definitions are appended to the content.
+    fn find_and_replace_refs(
+        &self,
+        s: &str,
+        chap_name: &str,
+        chap_path: &Path,
+        refs: &HashMap<String, LabelInfo>,
+        eqs: &[Equation],
+        summary: &mut NumEqSummary,
+    ) -> String {
+        // content without references (e.g., already processed content) is left untouched
+        if !REF_MARKER.is_match(s) {
+            return s.to_string();
+        }
+        // the footnote definitions (label and link), in order of first reference
+        let mut footnotes: Vec<(String, String)> = Vec::new();
+        // the label of the last reference to a single equation, and the end of this reference
+        let mut last_ref: Option<(String, usize)> = None;
+        let mut output = REF_MARKER
+            .replace_all(s, |caps: &regex::Captures| {
+                summary.references += 1;
+                let marker = caps.get(0).unwrap();
+                let label = unescape(caps["label"].trim());
+                let repeat = self.collapse_repeats > 0
+                    && matches!(&last_ref, Some((last, end))
+                        if *last == label && marker.start() - end <= self.collapse_repeats);
+                last_ref = refs
+                    .contains_key(&label)
+                    .then(|| (label.clone(), marker.end()));
+                if let (true, Some(info)) = (repeat, refs.get(&label)) {
+                    // a repeated reference is displayed without link
+                    let text = self.isolate(self.ref_text(info, chap_path));
+                    return match &caps["kind"] {
+                        "cref" => format!("{} {text}", self.cref_singular),
+                        _ => text,
+                    };
+                }
+                let mut plural = false;
+                let mut links: Vec<String> = Vec::new();
+                let mut items: Vec<&str> = caps["label"].split(',').map(str::trim).collect();
+                if self.dedup_refs {
+                    // repeated items are dropped, together with their line annotations
+                    let mut seen = HashSet::new();
+                    let mut dropped = false;
+                    items.retain(|item| {
+                        if !is_line_annotation(item) {
+                            dropped = !seen.insert(unescape(item));
+                        }
+                        !dropped
+                    });
+                }
+                let items = if self.collapse_ranges {
+                    self.collapse_runs(&items, refs, eqs)
+                } else {
+                    items.iter().map(|item| item.to_string()).collect()
+                };
+                for item in &items {
+                    let item = item.as_str();
+                    if let Some(("line", line)) =
+                        item.split_once('=').map(|(k, v)| (k.trim_end(), v))
+                    {
+                        // a line annotation of the previous reference
+                        match links.last_mut() {
+                            Some(link) => {
+                                append_to_link_text(link, &format!(", line {}", line.trim()))
+                            }
+                            None => warn!(
+                                "{}: Ignoring `{item}' not preceded by a reference",
+                                self.location(chap_name, chap_path)
+                            ),
+                        }
+                        continue;
+                    }
+                    let (first, last) = match item.split_once("..") {
+                        Some((first, last)) if !refs.contains_key(&unescape(item)) => {
+                            (first.trim(), Some(last.trim()))
+                        }
+                        _ => (item, None),
+                    };
+                    let mut link = self.isolate(self.resolve_label(
+                        &unescape(first),
+                        chap_name,
+                        chap_path,
+                        refs,
+                        eqs,
+                        summary,
+                        &mut footnotes,
+                    ));
+                    if let Some(last) = last {
+                        plural = true;
+                        link.push_str(&self.range_separator);
+                        link.push_str(&self.isolate(self.resolve_label(
+                            &unescape(last),
+                            chap_name,
+                            chap_path,
+                            refs,
+                            eqs,
+                            summary,
+                            &mut footnotes,
+                        )));
+                        if let Some(format) = &self.ref_format_range {
+                            match self.range_count(&unescape(first), &unescape(last), refs, eqs) {
+                                Some(count) => {
+                                    link = format
+                                        .replace("{range}", &link)
+                                        .replace("{count}", &count.to_string())
+                                }
+                                None => warn!(
+                                    "{}: Cannot count the equations of range `{item}'",
+                                    self.location(chap_name, chap_path)
+                                ),
+                            }
+                        }
+                    }
+                    links.push(link);
+                }
+                if &caps["kind"] == "cref" {
+                    let word = if plural || links.len() > 1 {
+                        &self.cref_plural
+                    } else {
+                        &self.cref_singular
+                    };
+                    format!("{word} {}", self.join_list(links))
+                } else {
+                    self.join_list(links)
+                }
+            })
+            .to_string();
+        if !footnotes.is_empty() {
+            output.push('\n');
+            for (label, link) in footnotes {
+                output.push_str(&format!("\n[^numeq-{label}]: {link}\n"));
+            }
+        }
+        output
+    }
+
+    /// Wraps `s` in a `<bdi>` element in right-to-left mode, so that numbers such as `(3)` are not reordered.
+    fn isolate(&self, s: String) -> String {
+        if self.rtl {
+            format!("<bdi>{s}</bdi>")
+        } else {
+            s
+        }
+    }
+
+    /// Returns the number of equations of the range from `first` to `last` (labels or positions in `eqs`),
+    /// computed from the counters of the endpoints, if they are known and have the same prefix.
+    fn range_count(
+        &self,
+        first: &str,
+        last: &str,
+        refs: &HashMap<String, LabelInfo>,
+        eqs: &[Equation],
+    ) -> Option<usize> {
+        let first = self.number_of(first, refs, eqs)?;
+        let last = self.number_of(last, refs, eqs)?;
+        match (split_number(&first)?, split_number(&last)?) {
+            ((p, a), (q, b)) if p == q && a <= b => Some(b - a + 1),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of the equation `label` (a label or a position in `eqs`), if known.
+    fn number_of(
+        &self,
+        label: &str,
+        refs: &HashMap<String, LabelInfo>,
+        eqs: &[Equation],
+    ) -> Option<String> {
+        let label = label
+            .split_once('|')
+            .map_or(label, |(label, _)| label.trim_end());
+        let label = self.shorten_label(label.to_string());
+        match refs.get(&label) {
+            Some(info) => Some(info.num.clone()),
+            None => label
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| eqs.get(n.checked_sub(1)?))
+                .map(|eq| eq.num.clone()),
+        }
+    }
+
+    /// Replaces the runs of at least three consecutive equations in the list of references `items`
+    /// by ranges `first..last`; items with a custom text, ranges and annotated items are left as is.
+    fn collapse_runs(
+        &self,
+        items: &[&str],
+        refs: &HashMap<String, LabelInfo>,
+        eqs: &[Equation],
+    ) -> Vec<String> {
+        let numbers: Vec<Option<(String, usize)>> = items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| {
+                if is_line_annotation(item)
+                    || item.contains("..")
+                    || item.contains('|')
+                    || items
+                        .get(i + 1)
+                        .is_some_and(|next| is_line_annotation(next))
+                {
+                    return None;
+                }
+                let num = self.number_of(&unescape(item), refs, eqs)?;
+                split_number(&num).map(|(p, n)| (p.to_string(), n))
+            })
+            .collect();
+        let mut output = Vec::new();
+        let mut i = 0;
+        while i < items.len() {
+            let mut j = i + 1;
+            if let Some((p, n)) = &numbers[i] {
+                while matches!(numbers.get(j), Some(Some((q, m))) if q == p && *m == n + (j - i)) {
+                    j += 1;
+                }
+            }
+            if j - i >= 3 {
+                output.push(format!("{}..{}", items[i], items[j - 1]));
+                i = j;
+            } else {
+                output.push(items[i].to_string());
+                i += 1;
+            }
+        }
+        output
+    }
+
+    /// Resolves the reference to `label` from the chapter at path `chap_path` (whose equations are `eqs`),
+    /// falling back to a warning and a placeholder if the label is unknown (in which case it is recorded in `summary`).
+    /// The label can be followed by `|text` to display `text` instead of the number as link text.
+    #[allow(clippy::too_many_arguments)]
+    fn resolve_label(
+        &self,
+        label: &str,
+        chap_name: &str,
+        chap_path: &Path,
+        refs: &HashMap<String, LabelInfo>,
+        eqs: &[Equation],
+        summary: &mut NumEqSummary,
+        footnotes: &mut Vec<(String, String)>,
+    ) -> String {
+        let (label, text) = match label.split_once('|') {
+            Some((label, text)) => (label.trim_end(), Some(text.trim_start())),
+            None => (label, None),
+        };
+        let label = &self.shorten_label(label.to_string());
+        let ordinal = match label.parse::<usize>() {
+            Ok(n) if n > 0 && !refs.contains_key(label) => Some(n),
+            _ => None,
+        };
+        match ordinal {
+            Some(n) => match eqs.get(n - 1) {
+                Some(Equation {
+                    label: Some(label), ..
+                }) => self
+                    .link_to(label, text, chap_path, refs, summary, footnotes)
+                    .unwrap(),
+                Some(Equation {
+                    num, label: None, ..
+                }) if self.auto_anchor && refs.contains_key(&auto_anchor_key(num)) => self
+                    .link_to(
+                        &auto_anchor_key(num),
+                        text,
+                        chap_path,
+                        refs,
+                        summary,
+                        footnotes,
+                    )
+                    .unwrap(),
+                // an unlabeled equation has no anchor, so we can only display its number (or the given text)
+                Some(Equation {
+                    num, label: None, ..
+                }) => text.map_or_else(
+                    || self.ref_format_for(true).replace("{n}", num),
+                    str::to_string,
+                ),
+                None => {
+                    warn!(
+                        "{}: Unknown equation reference: {label} (the chapter has only {} equations)",
+                        self.location(chap_name, chap_path),
+                        eqs.len()
+                    );
+                    summary
+                        .unresolved
+                        .push((label.to_string(), chap_path.to_path_buf()));
+                    "**[??]**".to_string()
+                }
+            },
+            None => match self
+                .link_to(label, text, chap_path, refs, summary, footnotes)
+                .or_else(|| {
+                    // with automatic anchors, equations can also be referenced by number
+                    let key = self
+                        .auto_anchor
+                        .then(|| {
+                            let loc = self.location(chap_name, chap_path);
+                            label_of_number(label, chap_path, &loc, refs)
+                        })
+                        .flatten()?;
+                    self.link_to(&key, text, chap_path, refs, summary, footnotes)
+                }) {
+                Some(link) => link,
+                None => {
+                    warn!(
+                        "{}: Unknown equation reference: {label}",
+                        self.location(chap_name, chap_path)
+                    );
+                    summary
+                        .unresolved
+                        .push((label.to_string(), chap_path.to_path_buf()));
+                    "**[??]**".to_string()
+                }
+            },
+        }
+    }
+
+    /// Returns the reference to the equation with label `label` from the chapter at path `chap_path`
+    /// (an inline link or a footnote reference, whose definition is then added to `footnotes`) and records it in `summary`,
+    /// or returns `None` if the label is unknown.
+    /// If `text` is given, the reference is always an inline link with this text.
+    fn link_to(
+        &self,
+        label: &str,
+        text: Option<&str>,
+        chap_path: &Path,
+        refs: &HashMap<String, LabelInfo>,
+        summary: &mut NumEqSummary,
+        footnotes: &mut Vec<(String, String)>,
+    ) -> Option<String> {
+        let link = self.format_ref(label, chap_path, refs, text)?;
+        summary.record_backref(label, chap_path);
+        if text.is_some() {
+            return Some(link);
+        }
+        if self.mathjax_native_refs && refs[label].path == chap_path {
+            return Some(format!("\\eqref{{{label}}}"));
+        }
+        match self.ref_style {
+            RefStyle::Link => Some(link),
+            RefStyle::Footnote => {
+                if !footnotes.iter().any(|(lb, _)| lb == label) {
+                    footnotes.push((label.to_string(), link));
+                }
+                Some(format!("[^numeq-{label}]"))
+            }
+        }
+    }
+
+    /// Removes the equation markers of `s` and replaces references by their plain text
+    /// (the labels, or the text given with `|text`), for the renderers of `strip_renderers`.
+    fn strip_markers(&self, s: &str) -> String {
+        let s = EQ_MARKER.replace_all(s, "");
+        let s = s
+            .replace("{{numeq-begin}}", "")
+            .replace("{{numeq-end}}", "");
+        REF_MARKER
+            .replace_all(&s, |caps: &regex::Captures| {
+                let items: Vec<String> = caps["label"]
+                    .split(',')
+                    .map(str::trim)
+                    // line annotations have no plain text equivalent
+                    .filter(|item| !matches!(item.split_once('='), Some((k, _)) if k.trim_end() == "line"))
+                    .map(|item| {
+                        let item = item
+                            .split_once('|')
+                            .map_or(item, |(_, text)| text.trim_start());
+                        unescape(&item.replace("..", &self.range_separator))
+                    })
+                    .collect();
+                let plural = items.len() > 1 || caps["label"].contains("..");
+                match &caps["kind"] {
+                    "cref" if plural => format!("{} {}", self.cref_plural, self.join_list(items)),
+                    "cref" => format!("{} {}", self.cref_singular, self.join_list(items)),
+                    _ => self.join_list(items),
+                }
+            })
+            .to_string()
+    }
+
+    /// Joins `items` with commas, using the list conjunction between the last two items
+    /// (preceded by a comma for lists of three items or more if `oxford_comma` is set).
+    fn join_list(&self, mut items: Vec<String>) -> String {
+        match items.pop() {
+            None => String::new(),
+            Some(last) if items.is_empty() => last,
+            Some(last) => {
+                let comma = if self.oxford_comma && items.len() > 1 {
+                    ","
+                } else {
+                    ""
+                };
+                format!(
+                    "{}{comma} {} {last}",
+                    items.join(", "),
+                    self.list_conjunction
+                )
+            }
+        }
+    }
+
+    /// Returns the Markdown link to the equation with label `label` from the chapter at path `from`
+    /// (with link text `text` if given), or `None` if the label is unknown.
+    fn format_ref(
+        &self,
+        label: &str,
+        from: &Path,
+        refs: &HashMap<String, LabelInfo>,
+        text: Option<&str>,
+    ) -> Option<String> {
+        let info = refs.get(label)?;
+        let text = match text {
+            Some(text) => text.to_string(),
+            None => self.ref_text(info, from),
+        };
+        let target = match &info.url {
+            // equations outside of the book are linked to by their URL
+            Some(url) => url.clone(),
+            None => {
+                let rel_path = compute_rel_path(from, &info.path);
+                let fragment = if self.mathjax_native_refs && self.output == Output::Tag {
+                    // the id given by MathJax to labeled equations
+                    format!("mjx-eqn-{}", label.replace(char::is_whitespace, "_"))
+                } else {
+                    info.id.clone().unwrap_or_else(|| self.anchor_id(label))
+                };
+                format!("{rel_path}#{fragment}")
+            }
+        };
+        Some(match &self.ref_class {
+            Some(class) => format!("<a class=\"{class}\" href=\"{target}\">{text}</a>"),
+            None => format!("[{text}]({target})"),
+        })
+    }
+
+    /// Returns the text of a reference to the equation described by `info` from the chapter at path `from`.
+    fn ref_text(&self, info: &LabelInfo, from: &Path) -> String {
+        let mut text = self
+            .ref_format_for(info.path == from)
+            .replace("{n}", &info.num)
+            .replace("{section}", info.section.as_deref().unwrap_or_default())
+            .replace("{title}", info.title.as_deref().unwrap_or_default());
+        if let (true, false, Some(title)) = (
+            self.show_chapter_on_crosspage,
+            info.path == from,
+            &info.title,
+        ) {
+            text = format!("{text} in {title}");
+        }
+        match (&info.caption, self.ref_with_caption) {
+            (Some(caption), true) => format!("{caption} {text}"),
+            _ => text,
+        }
+    }
+
+    /// Returns the format of references to equations of the same chapter if `local` is true,
+    /// and of other chapters otherwise.
+    fn ref_format_for(&self, local: bool) -> &str {
+        let format = if local {
+            &self.ref_format_local
+        } else {
+            &self.ref_format_remote
+        };
+        format.as_deref().unwrap_or(&self.ref_format)
+    }
+
+    /// Escapes the nested braces of the references of `s` and of its markers which are numbered
+    /// (see `escape_nested_braces`), so that markers left in the output are unchanged.
+    fn escape_nested_braces(&self, s: &str) -> String {
+        escape_nested_braces(s, self.processed_markers(s))
+    }
+
+    /// Returns whether a marker at a given offset of `s` is processed, i.e., is not left untouched
+    /// for being in a comment, outside of numbering regions, or in content with manual tags.
+    fn processed_markers(&self, s: &str) -> impl Fn(usize) -> bool {
+        let numbered = |s: &str| !(self.skip_manual_tags && MANUAL_TAG.is_match(s));
+        let all = !self.regions && numbered(s);
+        let regions: Vec<Range<usize>> = if self.regions {
+            NUMBERING_REGION
+                .captures_iter(s)
+                .map(|caps| caps.name("region").unwrap())
+                .filter(|region| numbered(region.as_str()))
+                .map(|region| region.range())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let comments = if self.skip_comments {
+            find_comments(s)
+        } else {
+            Vec::new()
+        };
+        move |i| {
+            (all || regions.iter().any(|r| r.contains(&i)))
+                && !comments.iter().any(|c| c.contains(&i))
+        }
+    }
+
+    /// Returns the label of an equation marker, given either in braces (`{{numeq}}{mylabel}`) or
+    /// with the colon syntax (`{{numeq:mylabel}}`), in which case known arguments take precedence.
+    fn marker_label(&self, caps: &regex::Captures) -> Option<String> {
+        if let Some(lb) = caps.name("label") {
+            return Some(unescape(lb.as_str()));
+        }
+        let arg = caps.name("arg")?.as_str().trim();
+        let known = arg == "endfamily"
+            || self.streams.iter().any(|name| name == arg)
+            || matches!(
+                arg.split_once('=').map(|(k, _)| k.trim_end()),
+                Some("family" | "start")
+            );
+        (!known).then(|| arg.to_string())
+    }
+
+    /// Returns the labels which are referenced in the same block (paragraph or math block
+    /// delimited by blank lines) as the equation they label.
+    fn find_self_refs(&self, s: &str) -> Vec<String> {
+        let mut self_refs = Vec::new();
+        for block in BLANK_LINES.split(s) {
+            let labels: Vec<String> = EQ_MARKER
+                .captures_iter(block)
+                .filter_map(|caps| self.marker_label(&caps))
+                .collect();
+            for caps in REF_MARKER.captures_iter(block) {
+                for lb in caps["label"].split(',').map(|lb| unescape(lb.trim())) {
+                    if labels.contains(&lb) && !self_refs.contains(&lb) {
+                        self_refs.push(lb);
+                    }
+                }
+            }
+        }
+        self_refs
+    }
+
+    /// Truncates a label longer than `max_label_len` characters, replacing its end by a hash of the
+    /// whole label, so that definitions and references are truncated the same way.
+    fn shorten_label(&self, label: String) -> String {
+        match self.max_label_len {
+            Some(max) if label.chars().count() > max => {
+                // FNV-1a, which (unlike the hasher of the standard library) is stable across Rust versions
+                let hash = label.bytes().fold(0x811c9dc5u32, |h, b| {
+                    (h ^ u32::from(b)).wrapping_mul(0x01000193)
+                });
+                let head: String = label
+                    .chars()
+                    .take(max.saturating_sub(MIN_LABEL_LEN))
+                    .collect();
+                format!("{head}-{hash:08x}")
+            }
+            _ => label,
+        }
+    }
+
+    /// Returns the location of the chapter named `name` at path `path`, to be prepended to warnings
+    /// (the name is omitted if empty), preceded by the annotation command of the CI service, if any.
+    fn location(&self, name: &str, path: &Path) -> String {
+        self.location_at(name, path, None)
+    }
+
+    /// Like `location`, with the position `pos` (line and column) in the chapter, if known,
+    /// which is given to the annotation command of the CI service.
+    fn location_at(&self, name: &str, path: &Path, pos: Option<(usize, usize)>) -> String {
+        let location = if name.is_empty() {
+            format!("In {}", path.display())
+        } else {
+            format!("In chapter \"{name}\" ({})", path.display())
+        };
+        // property values of workflow commands are percent-encoded (e.g., commas of paths)
+        let file = self
+            .src_dir
+            .join(path)
+            .display()
+            .to_string()
+            .replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+            .replace(':', "%3A")
+            .replace(',', "%2C");
+        match (self.ci, pos) {
+            (CiFormat::Plain, _) => location,
+            (CiFormat::Github, None) => format!("::warning file={file}::{location}"),
+            (CiFormat::Github, Some((line, col))) => {
+                format!("::warning file={file},line={line},col={col}::{location}")
+            }
+        }
+    }
+
+    /// Returns the error making the build fail with messages `errors`, which are annotated
+    /// as errors (rather than warnings) for the CI service, if any.
+    fn failure(&self, errors: &[String]) -> Error {
+        match self.ci {
+            CiFormat::Plain => Error::msg(errors.join("\n")),
+            // each annotation command must start a line
+            CiFormat::Github => Error::msg(
+                errors
+                    .iter()
+                    .map(|e| {
+                        format!(
+                            "\n{}",
+                            escape_annotation(&e.replacen("::warning ", "::error ", 1))
+                        )
+                    })
+                    .collect::<String>(),
+            ),
+        }
+    }
+
+    /// Returns the HTML id of the anchor of the equation with label `label`,
+    /// the braces of the label (which may be unbalanced in `\htmlId`) being replaced by dashes.
+    fn anchor_id(&self, label: &str) -> String {
+        let id = label.replace(['{', '}'], "-");
+        match &self.colon_replacement {
+            Some(r) => id.replace(':', r),
+            None => id,
+        }
+    }
+}
+
+/// Percent-encodes the message data (`%`, `\r` and `\n`) of an annotation command of GitHub Actions
+/// such as `::warning file=a.md::message`, which would otherwise be cut or misread; other messages
+/// are returned unchanged.
+///
+/// ```
+/// use mdbook_numeq::escape_annotation;
+///
+/// assert_eq!(
+///     escape_annotation("::warning file=a.md::Label `eq:100%' already used\n"),
+///     "::warning file=a.md::Label `eq:100%25' already used%0A"
+/// );
+/// assert_eq!(escape_annotation("Label `eq:100%'"), "Label `eq:100%'");
+/// ```
+pub fn escape_annotation(msg: &str) -> String {
+    match msg.strip_prefix("::").and_then(|m| m.split_once("::")) {
+        Some((command, data)) => format!(
+            "::{command}::{}",
+            data.replace('%', "%25")
+                .replace('\r', "%0D")
+                .replace('\n', "%0A")
+        ),
+        None => msg.to_string(),
+    }
+}
+
+/// Resolves a reference to the equation with label `label` from the chapter at path `from`
+/// into a Markdown link (using the default reference format), or returns `None` if the label is unknown.
+///
+/// ```
+/// use mdbook_numeq::{resolve_ref, LabelInfo};
+/// use std::collections::HashMap;
+/// use std::path::Path;
+///
+/// let mut refs = HashMap::new();
+/// refs.insert(
+///     "eq:test".to_string(),
+///     LabelInfo {
+///         num: "1.2.1".to_string(),
+///         path: "crypto/groups.md".into(),
+///         ..Default::default()
+///     },
+/// );
+///
+/// assert_eq!(
+///     resolve_ref("eq:test", Path::new("crypto/intro.md"), &refs),
+///     Some("[(1.2.1)](groups.md#eq:test)".to_string())
+/// );
+/// assert_eq!(
+///     resolve_ref("eq:test", Path::new("fields.md"), &refs),
+///     Some("[(1.2.1)](crypto/groups.md#eq:test)".to_string())
+/// );
+/// assert_eq!(resolve_ref("eq:unknown", Path::new("fields.md"), &refs), None);
+/// ```
+pub fn resolve_ref(label: &str, from: &Path, refs: &HashMap<String, LabelInfo>) -> Option<String> {
+    NumEqPreprocessor::default().format_ref(label, from, refs, None)
+}
+
+/// Parses a section number such as `1.2.` into its components,
+/// emitting a warning (with location `loc`) and returning `None` if a component is not a valid integer.
+fn parse_section_number(prefix: &str, loc: &str) -> Option<Vec<usize>> {
+    let parsed = prefix
+        .trim_end_matches('.')
+        .split('.')
+        .map(|s| s.parse::<usize>())
+        .collect::<std::result::Result<Vec<usize>, _>>();
+    match parsed {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!("{loc}: Invalid section number `{prefix}': {e}");
+            None
+        }
+    }
+}
+
+/// Returns the value of the integer option `option` of the preprocessor, if any,
+/// emitting a warning and returning `None` if it is not a valid `usize` (e.g., if it is negative).
+fn get_usize(config: &mdbook::Config, option: &str) -> Option<usize> {
+    let toml::Value::Integer(n) = config.get(&format!("preprocessor.numeq.{option}"))? else {
+        return None;
+    };
+    usize::try_from(*n)
+        .map_err(|_| {
+            warn!(
+                "Ignoring invalid value `{n}' for option {option}, expected a non-negative integer"
+            )
+        })
+        .ok()
+}
+
+/// Returns whether the item `item` of a list of references is a line annotation `line=n`.
+fn is_line_annotation(item: &str) -> bool {
+    item.split_once('=')
+        .is_some_and(|(k, _)| k.trim_end() == "line")
+}
+
+/// Applies the transformation `transform` to the equation number `num`.
+fn transform_number(num: &str, transform: NumberTransform) -> String {
+    match transform {
+        NumberTransform::None => num.to_string(),
+        NumberTransform::Upper => num.to_uppercase(),
+        NumberTransform::ThinSpaceGroups => {
+            let mut output = String::new();
+            let mut digits = String::new();
+            // the characters are followed by a sentinel flushing the last run of digits
+            for c in num.chars().map(Some).chain([None]) {
+                match c {
+                    Some(c) if c.is_ascii_digit() => digits.push(c),
+                    _ => {
+                        if digits.len() > 4 {
+                            for (i, d) in digits.chars().enumerate() {
+                                // a thin space precedes each group of three digits ending the run
+                                if i > 0 && i % 3 == digits.len() % 3 {
+                                    output.push('\u{2009}');
+                                }
+                                output.push(d);
+                            }
+                        } else {
+                            output.push_str(&digits);
+                        }
+                        digits.clear();
+                        output.extend(c);
+                    }
+                }
+            }
+            output
+        }
+    }
+}
+
+/// Escapes the characters of `s` which are special in TeX text mode (e.g., `_`).
+fn escape_tex(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        if "#$%&_{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes the balanced braces nested in the labels (and captions) of markers and references of `s`,
+/// e.g., `{{numeq}}{eq:{sub}}` becomes `{{numeq}}{eq:\{sub\}}`, so that they are part of the labels.
+/// Markers at offsets for which `numbered` returns false are left untouched.
+fn escape_nested_braces(s: &str, numbered: impl Fn(usize) -> bool) -> String {
+    let mut output = String::new();
+    let mut last = 0;
+    for m in MARKER_OPENING.find_iter(s) {
+        if m.start() < last || (!m.as_str().ends_with(':') && !numbered(m.start())) {
+            continue;
+        }
+        output.push_str(&s[last..m.end()]);
+        last = m.end();
+        if m.as_str().ends_with(':') {
+            // the labels of a reference, up to the closing `}}`
+            if let Some((escaped, end)) = escape_group(s, last) {
+                output.push_str(&escaped);
+                last = end;
+            }
+        } else {
+            // the label and the caption of a marker
+            for _ in 0..2 {
+                if !s[last..].starts_with('{') {
+                    break;
+                }
+                match escape_group(s, last + 1) {
+                    Some((escaped, end)) => {
+                        output.push('{');
+                        output.push_str(&escaped);
+                        output.push('}');
+                        last = end + 1;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    output.push_str(&s[last..]);
+    output
+}
+
+/// Escapes the braces of `s` from offset `start` up to the first closing brace which is not balanced,
+/// returning the escaped text and the offset of this brace, or `None` if a line break comes first.
+fn escape_group(s: &str, start: usize) -> Option<(String, usize)> {
+    let mut escaped = String::new();
+    let mut depth = 0;
+    let mut chars = s[start..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                // already escaped characters are kept as is
+                escaped.push(c);
+                match chars.next() {
+                    Some((_, '\n')) | None => return None,
+                    Some((_, next)) => escaped.push(next),
+                }
+            }
+            '{' => {
+                depth += 1;
+                escaped.push_str("\\{");
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                escaped.push_str("\\}");
+            }
+            '}' => return Some((escaped, start + i)),
+            '\n' => return None,
+            _ => escaped.push(c),
+        }
+    }
+    None
+}
+
+/// Unescapes the braces `\{` and `\}` of a label or caption.
+fn unescape(s: &str) -> String {
+    s.replace("\\{", "{").replace("\\}", "}")
+}
+
+/// Returns the letters of the `n`-th appendix: A, B, ..., Z, AA, AB, etc.
+/// (also used, in lowercase, for the members of equation families).
+fn appendix_letter(mut n: usize) -> String {
+    let mut letters = Vec::new();
+    while n > 0 {
+        n -= 1;
+        letters.push((b'A' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    letters.iter().rev().collect()
+}
+
+/// Formats a component of a section number in the given style (0 is always written as is).
+fn format_component(n: usize, style: ComponentStyle) -> String {
+    match style {
+        ComponentStyle::Roman if n > 0 => {
+            const NUMERALS: [(usize, &str); 13] = [
+                (1000, "M"),
+                (900, "CM"),
+                (500, "D"),
+                (400, "CD"),
+                (100, "C"),
+                (90, "XC"),
+                (50, "L"),
+                (40, "XL"),
+                (10, "X"),
+                (9, "IX"),
+                (5, "V"),
+                (4, "IV"),
+                (1, "I"),
+            ];
+            let mut n = n;
+            let mut roman = String::new();
+            for (value, numeral) in NUMERALS {
+                while n >= value {
+                    roman.push_str(numeral);
+                    n -= value;
+                }
+            }
+            roman
+        }
+        ComponentStyle::Alpha if n > 0 => appendix_letter(n),
+        _ => n.to_string(),
+    }
+}
+
+/// Returns the number of the equation with counter `n` and prefix `prefix`, the counter being
+/// parenthesized if the prefix ends with an opening parenthesis (e.g., `1.2(3)`).
+fn compose_number(prefix: &str, n: usize) -> String {
+    match prefix.ends_with('(') {
+        true => format!("{prefix}{n})"),
+        false => format!("{prefix}{n}"),
+    }
+}
+
+/// Splits an equation number into its prefix and its counter, e.g., `1.2.3` into `1.2.` and 3
+/// or `1.2(3)` into `1.2(` and 3 (ignoring the letter of a family member, as in `1.2.3b`).
+fn split_number(num: &str) -> Option<(&str, usize)> {
+    let num = num.trim_end_matches(|c: char| c.is_ascii_lowercase());
+    let num = num.strip_suffix(')').unwrap_or(num);
+    let prefix = num.trim_end_matches(|c: char| c.is_ascii_digit());
+    num[prefix.len()..].parse().ok().map(|n| (prefix, n))
+}
+
+/// Returns the label (possibly synthetic) of the equation numbered `num` (optionally in parentheses),
+/// preferring equations of the chapter at path `chap_path` (with location `loc` in warnings)
+/// if several have this number.
+fn label_of_number(
+    num: &str,
+    chap_path: &Path,
+    loc: &str,
+    refs: &HashMap<String, LabelInfo>,
+) -> Option<String> {
+    let num = num
+        .strip_prefix('(')
+        .and_then(|n| n.strip_suffix(')'))
+        .unwrap_or(num)
+        .trim();
+    let mut matches: Vec<(&String, &LabelInfo)> =
+        refs.iter().filter(|(_, info)| info.num == num).collect();
+    // equations of the chapter first, then in order of labels for determinism
+    matches.sort_by_key(|(label, info)| (info.path != chap_path, *label));
+    match matches[..] {
+        [] => None,
+        [(label, _)] => Some(label.clone()),
+        [(label, first), (_, second), ..] => {
+            if (first.path == chap_path) == (second.path == chap_path) {
+                warn!("{loc}: Equation number {num} is ambiguous, linking to `{label}'");
+            }
+            Some(label.clone())
+        }
+    }
+}
+
+/// Returns the synthetic label of an unlabeled equation with number `num`, e.g., `eq-1-2-1` for 1.2.1.
+fn auto_anchor_key(num: &str) -> String {
+    let id: String = num
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("eq-{id}")
+}
+
+/// Removes the Markdown formatting of a chapter name: emphasis with `*`, strikethrough, code spans and links.
+fn strip_markdown(name: &str) -> String {
+    MD_LINK
+        .replace_all(name, "$text")
+        .replace(['*', '~', '`'], "")
+}
+
+/// Returns a slug of `name`: lowercase alphanumeric words separated by hyphens.
+fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+/// Appends `suffix` to the text of the Markdown (or HTML) link `link`, or to `link` itself if it is not a link
+/// (e.g., a footnote reference or plain text).
+fn append_to_link_text(link: &mut String, suffix: &str) {
+    // the suffix stays inside the `<bdi>` element of right-to-left mode
+    let inner = link.strip_prefix("<bdi>").unwrap_or(link);
+    let end = if inner.len() < link.len() {
+        link.len() - "</bdi>".len()
+    } else {
+        link.len()
+    };
+    match (link.rfind("]("), link.rfind("</a>")) {
+        (Some(i), _) if inner.starts_with('[') && !inner.starts_with("[^") => {
+            link.insert_str(i, suffix)
+        }
+        (_, Some(i)) if inner.starts_with("<a ") => link.insert_str(i, suffix),
+        _ => link.insert_str(end, suffix),
+    }
+}
+
+/// Parses the metadata `key1=value1, key2=value2` of the equation with number `num`
+/// (with location `loc` in warnings).
+fn parse_meta(s: &str, num: &str, loc: &str) -> HashMap<String, String> {
+    let mut meta = HashMap::new();
+    for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        match item.split_once('=') {
+            Some((key, value)) => {
+                meta.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            None => {
+                warn!("{loc}: Eq. {num}: Ignoring metadata `{item}' which is not of the form key=value")
+            }
+        }
+    }
+    meta
+}
+
+/// Removes all chapter-level directives `<!-- numeq: directive -->` from `content` and returns them
+/// (with location `loc` in warnings).
+fn take_directives(content: &mut String, loc: &str) -> Vec<String> {
+    let directives: Vec<String> = DIRECTIVE
+        .captures_iter(content)
+        .map(|caps| caps["directive"].to_string())
+        .collect();
+    for directive in &directives {
+        if !(directive == "group" || directive.starts_with("tag=")) {
+            warn!("{loc}: Unknown directive `{directive}'");
+        }
+    }
+    if !directives.is_empty() {
+        *content = DIRECTIVE.replace_all(content, "").to_string();
+    }
+    directives
+}
+
+/// Returns whether the position `pos` of `s` is on a line of a Markdown table,
+/// i.e., a line starting with `|` (tables without leading pipes are not detected).
+fn in_table_row(s: &str, pos: usize) -> bool {
+    let start = s[..pos].rfind('\n').map_or(0, |i| i + 1);
+    s[start..].trim_start().starts_with('|')
+}
+
+/// Loads the label map persisted at `path` by a previous run, if any.
+fn load_label_map(path: &Path) -> HashMap<String, LabelInfo> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignoring invalid label cache {}: {e}", path.display());
+        HashMap::new()
+    })
+}
+
+/// Serializes `value` as JSON into the file at `path`, warning on failure (`what` describes the file).
+fn save_json<T: Serialize>(path: &Path, value: &T, what: &str) {
+    let result = serde_json::to_string_pretty(value)
+        .map_err(Error::from)
+        .and_then(|json| Ok(fs::write(path, json)?));
+    if let Err(e) = result {
+        warn!("Could not write {what} {}: {e}", path.display());
+    }
+}
+
+/// Returns the byte ranges of the display math blocks `$$ ... $$` and `\[ ... \]` of `s` (delimiters included,
+/// the latter possibly with doubled backslashes `\\[ ... \\]` as written in Markdown).
+fn find_math_blocks(s: &str) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while let Some(open) = MATH_OPENING.find_at(s, pos) {
+        let close = match open.as_str() {
+            "$$" => "$$",
+            _ => "\\]",
+        };
+        match s[open.end()..].find(close) {
+            Some(i) => {
+                let end = open.end() + i + close.len();
+                blocks.push(open.start()..end);
+                pos = end;
+            }
+            None => break,
+        }
+    }
+    blocks
+}
+
+/// Returns the byte offsets of the ATX headings of level `level` (e.g., `## Title` for level 2) of `s`,
+/// outside of math blocks.
+fn find_headings(s: &str, level: usize) -> Vec<usize> {
+    let blocks = find_math_blocks(s);
+    let hashes = "#".repeat(level);
+    let mut headings = Vec::new();
+    let mut offset = 0;
+    for line in s.split_inclusive('\n') {
+        if let Some(rest) = line.strip_prefix(&hashes) {
+            if (rest.starts_with([' ', '\t']) || rest.trim().is_empty())
+                && !blocks.iter().any(|b| b.contains(&offset))
+            {
+                headings.push(offset);
+            }
+        }
+        offset += line.len();
+    }
+    headings
+}
+
+/// Returns the line and column (both starting at 1, the column counted in characters) of byte offset `offset` of `s`.
+fn line_col(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Returns the position (line and column) of a text of which position `pos` is relative
+/// to a part of the text starting at position `start`.
+fn shift_pos(start: (usize, usize), pos: (usize, usize)) -> (usize, usize) {
+    match pos {
+        (1, column) => (start.0, start.1 + column - 1),
+        (line, column) => (start.0 + line - 1, column),
+    }
+}
+
+/// Returns the range `range` of `s` extended to its whole line and one adjacent line break
+/// if the rest of the line is made of whitespace, or `range` itself otherwise.
+fn line_of(s: &str, range: Range<usize>) -> Range<usize> {
+    let start = s[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    let end = s[range.end..].find('\n').map_or(s.len(), |i| range.end + i);
+    let blank = |part: &str| part.chars().all(char::is_whitespace);
+    if !blank(&s[start..range.start]) || !blank(&s[range.end..end]) {
+        range
+    } else if end < s.len() {
+        // the line break ending the line
+        start..end + 1
+    } else if start > 0 {
+        // the last line of `s` takes the line break preceding it
+        start - 1..end
+    } else {
+        range
+    }
+}
+
+/// Returns the byte ranges of the HTML comments `<!-- ... -->` of `s` (delimiters included),
+/// an unterminated comment extending to the end of `s`.
+fn find_comments(s: &str) -> Vec<Range<usize>> {
+    let mut comments = Vec::new();
+    let mut from = 0;
+    while let Some(start) = s[from..].find("<!--").map(|i| from + i) {
+        let end = s[start + 4..]
+            .find("-->")
+            .map_or(s.len(), |i| start + 4 + i + 3);
+        comments.push(start..end);
+        from = end;
+    }
+    comments
+}
+
+/// Applies `edits`, given as non-overlapping (range, replacement) pairs, to `s`.
+fn apply_edits(s: &str, mut edits: Vec<(Range<usize>, String)>) -> String {
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut output = String::with_capacity(s.len());
+    let mut last = 0;
+    for (range, replacement) in edits {
+        output.push_str(&s[last..range.start]);
+        output.push_str(&replacement);
+        last = range.end;
+    }
+    output.push_str(&s[last..]);
+    output
+}
+
+/// Computes the relative path from the folder containing `chap_path` to the file `path_to_ref`.
+///
+/// The returned path is always relative (never starting with `/`) so that links keep working
+/// when the book is served under a sub-path of the site root.
+fn compute_rel_path(chap_path: &Path, path_to_ref: &Path) -> String {
+    if chap_path == path_to_ref {
+        return "".to_string();
+    }
+    let mut local_chap_path = chap_path.to_path_buf();
+    local_chap_path.pop();
+    format!(
+        "{}",
+        diff_paths(path_to_ref, &local_chap_path).unwrap().display()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mdbook::book::{Chapter, SectionNumber};
+    use mdbook::preprocess::CmdPreprocessor;
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    const SECNUM: &str = "1.2.";
+
+    lazy_static! {
+        static ref PATH: PathBuf = "crypto/groups.md".into();
+    }
+
+    /// A logger recording log messages of the current thread, so that tests can check diagnostics.
+    struct TestLogger;
+
+    thread_local! {
+        static LOGS: RefCell<Vec<(log::Level, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGS.with(|logs| {
+                logs.borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger;
+    static INIT_LOGGER: Once = Once::new();
+
+    /// Runs `f` and returns the messages logged in the meantime.
+    fn capture_logs<F: FnOnce()>(f: F) -> Vec<(log::Level, String)> {
+        INIT_LOGGER.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LOGS.with(|logs| logs.borrow_mut().clear());
+        f();
+        LOGS.with(|logs| logs.borrow_mut().drain(..).collect())
+    }
+
+    /// Runs `f` and returns the warnings logged in the meantime.
+    fn capture_warnings<F: FnOnce()>(f: F) -> Vec<String> {
+        capture_logs(f)
+            .into_iter()
+            .filter(|(level, _)| *level == log::Level::Warn)
+            .map(|(_, msg)| msg)
+            .collect()
+    }
+
+    /// Builds a preprocessor context from the content of a `book.toml` file.
+    fn context(config: &str) -> PreprocessorContext {
+        let input = serde_json::json!([
+            {
+                "root": "/path/to/book",
+                "config": toml::from_str::<toml::Value>(config).unwrap(),
+                "renderer": "html",
+                "mdbook_version": mdbook::MDBOOK_VERSION,
+            },
+            Book::new(),
+        ]);
+        let (ctx, _) = CmdPreprocessor::parse_input(input.to_string().as_bytes()).unwrap();
+        ctx
+    }
+
+    /// Builds a (top-level) chapter with the given section number.
+    fn chapter(name: &str, content: &str, path: &str, number: &[u32]) -> Chapter {
+        let mut ch = Chapter::new(name, content.to_string(), path, Vec::new());
+        if !number.is_empty() {
+            ch.number = Some(SectionNumber(number.to_vec()));
+        }
+        ch
+    }
+
+    /// Runs the preprocessor configured by `config` on a book made of `chapters`
+    /// and returns the content of each chapter (in depth-first order).
+    fn run_book(config: &str, chapters: Vec<Chapter>) -> Vec<String> {
+        let ctx = context(config);
+        let mut book = Book::new();
+        for ch in chapters {
+            book.push_item(ch);
+        }
+        let book = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+        book.iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch.content.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_label() {
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        let input = String::from(r"{{numeq}}");
+        let output = NumEqPreprocessor::default().find_and_replace_eqs(
+            &input,
+            SECNUM,
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        let expected = String::from("\\tag{1.2.1}");
+        assert_eq!(output, expected);
+        assert!(refs.is_empty());
+    }
+
+    #[test]
+    fn with_label() {
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        let input = String::from(r"{{numeq}}{eq:test}");
+        let output = NumEqPreprocessor::default().find_and_replace_eqs(
+            &input,
+            SECNUM,
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        let expected = String::from("\\htmlId{eq:test}{} \\tag{1.2.1}");
+        assert_eq!(output, expected);
+        assert_eq!(
+            *refs.get("eq:test").unwrap(),
+            LabelInfo {
+                num: "1.2.1".to_string(),
+                path: "crypto/groups.md".into(),
+                caption: None,
+                section: None,
+                title: None,
+                meta: HashMap::new(),
+                id: None,
+                url: None,
+            }
+        )
+    }
+
+    #[test]
+    fn ref_default_format() {
+        let pre = NumEqPreprocessor::default();
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        NumEqPreprocessor::default().find_and_replace_eqs(
+            "{{numeq}}{eq:test}",
+            SECNUM,
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        let output = pre.find_and_replace_refs(
+            "see {{eqref: eq:test}}",
+            "Groups",
+            &PATH,
+            &refs,
+            &[],
+            &mut NumEqSummary::default(),
+        );
+        assert_eq!(output, "see [(1.2.1)](#eq:test)");
+    }
+
+    #[test]
+    fn ref_format_without_brackets() {
+        let pre = NumEqPreprocessor {
+            ref_format: "{n}".to_string(),
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        NumEqPreprocessor::default().find_and_replace_eqs(
+            "{{numeq}}{eq:test}",
+            SECNUM,
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        let output = pre.find_and_replace_refs(
+            "see equation {{eqref: eq:test}}",
+            "Introduction",
+            Path::new("crypto/intro.md"),
+            &refs,
+            &[],
+            &mut NumEqSummary::default(),
+        );
+        assert_eq!(output, "see equation [1.2.1](groups.md#eq:test)");
+    }
+
+    #[test]
+    fn links_are_relative() {
+        let mut intro = chapter(
+            "Intro",
+            "{{numeq}}{eq:a} {{eqref: eq:a}} {{eqref: eq:b}}",
+            "intro.md",
+            &[1],
+        );
+        intro.sub_items.push(BookItem::Chapter(chapter(
+            "Groups",
+            "{{numeq}}{eq:b} {{eqref: eq:a}} {{eqref: eq:c}}",
+            "crypto/groups.md",
+            &[1, 1],
+        )));
+        let fields = chapter(
+            "Fields",
+            "{{numeq}}{eq:c} {{eqref: eq:b}}",
+            "algebra/fields/fields.md",
+            &[2],
+        );
+        let contents = run_book("", vec![intro, fields]);
+        let re = Regex::new(r"\]\((?P<target>[^)]*)\)").unwrap();
+        let targets: Vec<&str> = contents
+            .iter()
+            .flat_map(|c| {
+                re.captures_iter(c)
+                    .map(|caps| caps.name("target").unwrap().as_str())
+            })
+            .collect();
+        assert_eq!(targets.len(), 5);
+        assert!(targets.iter().all(|t| !t.starts_with('/')));
+        assert!(targets.contains(&"../algebra/fields/fields.md#eq:c"));
+        assert!(targets.contains(&"../../crypto/groups.md#eq:b"));
+    }
+
+    #[test]
+    fn regions() {
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        let input = "Write `{{numeq}}` after the equation:\n\
+                     {{numeq-begin}}\n$$ a = b {{numeq}}{eq:a} $$\n{{numeq-end}}\n\
+                     and `{{numeq}}{label}` to label it:\n\
+                     {{numeq-begin}}$$ c = d {{numeq}} $${{numeq-end}}";
+        let output = NumEqPreprocessor::default().find_and_replace_eqs_in_regions(
+            input,
+            SECNUM,
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        let expected = "Write `{{numeq}}` after the equation:\n\
+                        \n$$ a = b \\htmlId{eq:a}{} \\tag{1.2.1} $$\n\n\
+                        and `{{numeq}}{label}` to label it:\n\
+                        $$ c = d \\tag{1.2.2} $$";
+        assert_eq!(output, expected);
+        assert_eq!(ctr.main, 2);
+        assert_eq!(refs.len(), 1);
+    }
+
+    #[test]
+    fn regions_option() {
+        let content = "`{{numeq}}` {{numeq-begin}}$$ a {{numeq}} $${{numeq-end}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nregions = true",
+            vec![chapter("Intro", content, "intro.md", &[1])],
+        );
+        assert_eq!(contents[0], "`{{numeq}}` $$ a \\tag{1} $$");
+        let contents = run_book("", vec![chapter("Intro", content, "intro.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "`\\tag{1}` {{numeq-begin}}$$ a \\tag{2} $${{numeq-end}}"
+        );
+    }
+
+    #[test]
+    fn debug_statistics() {
+        let logs = capture_logs(|| {
+            run_book(
+                "",
+                vec![chapter(
+                    "Intro",
+                    "{{numeq}}{eq:a} {{numeq}} {{eqref: eq:a}} {{eqref: eq:a}} {{eqref: eq:b}}",
+                    "intro.md",
+                    &[1],
+                )],
+            );
+        });
+        let debug: Vec<&String> = logs
+            .iter()
+            .filter(|(level, _)| *level == log::Level::Debug)
+            .map(|(_, msg)| msg)
+            .collect();
+        assert_eq!(debug.len(), 2);
+        assert!(debug[0].starts_with("Numbered 2 equations in "));
+        assert!(debug[0].ends_with("(labels defined: 1)"));
+        assert!(debug[1].starts_with("Processed 3 equation references in "));
+    }
+
+    #[test]
+    fn depth_from_top_and_bottom() {
+        let chapters = || {
+            vec![
+                chapter("A", "{{numeq}} {{numeq}}", "a.md", &[1, 2, 3, 4]),
+                chapter("B", "{{numeq}}", "b.md", &[1, 2, 3, 5]),
+                chapter("C", "{{numeq}}", "c.md", &[2]),
+            ]
+        };
+        let contents = run_book("[preprocessor.numeq]\nprefix = true\ndepth = 2", chapters());
+        assert_eq!(contents[0], "\\tag{1.2.1} \\tag{1.2.2}");
+        assert_eq!(contents[1], "\\tag{1.2.3}");
+        assert_eq!(contents[2], "\\tag{2.0.1}");
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\ndepth = 2\ndepth_from = \"bottom\"",
+            chapters(),
+        );
+        assert_eq!(contents[0], "\\tag{3.4.1} \\tag{3.4.2}");
+        assert_eq!(contents[1], "\\tag{3.5.1}");
+        assert_eq!(contents[2], "\\tag{2.0.1}");
+    }
+
+    #[test]
+    fn whitespace_is_preserved() {
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        // marker on its own line
+        let input = "$$\na = b\n{{numeq}}\n$$\n\nNext paragraph.";
+        let output = NumEqPreprocessor::default().find_and_replace_eqs(
+            input,
+            "",
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "$$\na = b\n\\tag{1}\n$$\n\nNext paragraph.");
+        // marker at the end of a line
+        let input = "$$\na = b {{numeq}}{eq:a}\n$$\n";
+        let output = NumEqPreprocessor::default().find_and_replace_eqs(
+            input,
+            "",
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "$$\na = b \\htmlId{eq:a}{} \\tag{2}\n$$\n");
+        // inline marker
+        let input = "$$ a = b {{numeq}} $$ and text";
+        let output = NumEqPreprocessor::default().find_and_replace_eqs(
+            input,
+            "",
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "$$ a = b \\tag{3} $$ and text");
+    }
+
+    #[test]
+    fn number_collisions() {
+        let chapters = || {
+            vec![
+                chapter("A", "{{numeq}}", "a.md", &[1]),
+                chapter("B", "{{numeq}} {{numeq}}", "b.md", &[2]),
+            ]
+        };
+        let warnings = capture_warnings(|| {
+            run_book("[preprocessor.numeq]\nwarn_collisions = true", chapters());
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "In chapter \"B\" (b.md): Eq. 1: Number already used in a.md, references by number would be ambiguous"
+            ]
+        );
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]\nwarn_collisions = true\nprefix = true",
+                chapters(),
+            );
+        });
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn ordinal_refs() {
+        let contents = run_book(
+            "",
+            vec![
+                chapter(
+                    "A",
+                    "{{numeq}} {{numeq}}{eq:a} {{eqref: 2}} {{eqref: 1}} {{eqref: 3}}",
+                    "a.md",
+                    &[1],
+                ),
+                chapter(
+                    "B",
+                    "{{numeq}}{eq:b} {{eqref: 2}} {{eqref: 1}}",
+                    "b.md",
+                    &[2],
+                ),
+            ],
+        );
+        assert_eq!(
+            contents[0],
+            "\\tag{1} \\htmlId{eq:a}{} \\tag{2} [(2)](#eq:a) (1) **[??]**"
+        );
+        assert_eq!(
+            contents[1],
+            "\\htmlId{eq:b}{} \\tag{1} **[??]** [(1)](#eq:b)"
+        );
+    }
+
+    #[test]
+    fn data_attr_output() {
+        let pre = NumEqPreprocessor {
+            output: Output::DataAttr,
+            ..Default::default()
+        };
+        let mut refs = HashMap::new();
+        let mut ctr = Counters::default();
+        let input = "Text\n$$\na = b {{numeq}}{eq:a}\n$$\nmore text $$ c {{numeq}} $$";
+        let output = pre.find_and_replace_eqs(
+            input,
+            SECNUM,
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        let expected = "Text\n\
+                        <div class=\"numeq\" data-eqnum=\"1.2.1\" id=\"eq:a\">\n\n$$\na = b \n$$\n\n</div>\n\
+                        more text <div class=\"numeq\" data-eqnum=\"1.2.2\">\n\n$$ c  $$\n\n</div>";
+        assert_eq!(output, expected);
+        assert!(!output.contains("\\tag"));
+        assert_eq!(refs.get("eq:a").unwrap().num, "1.2.1");
+    }
+
+    #[test]
+    fn data_attr_output_fallback() {
+        let pre = NumEqPreprocessor {
+            output: Output::DataAttr,
+            ..Default::default()
+        };
+        let mut ctr = Counters::default();
+        let mut output = String::new();
+        let warnings = capture_warnings(|| {
+            output = pre.find_and_replace_eqs(
+                "\\( a {{numeq}} \\) $$ b {{numeq}} \\\\ c {{numeq}} $$",
+                "",
+                "Groups",
+                &PATH,
+                &mut HashMap::new(),
+                &mut ctr,
+                &mut Vec::new(),
+            );
+        });
+        assert_eq!(
+            output,
+            "\\( a \\tag{1} \\) <div class=\"numeq\" data-eqnum=\"2\">\n\n$$ b  \\\\ c  $$\n\n</div>"
+        );
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(ctr.main, 2);
+    }
+
+    #[test]
+    fn idempotent_run() {
+        let ctx = context("[preprocessor.numeq]\nprefix = true");
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "$$ a {{numeq}}{eq:a} $$ {{eqref: eq:b}} {{eqref: eq:c}}",
+            "a.md",
+            &[1],
+        ));
+        book.push_item(chapter(
+            "B",
+            "$$ b {{numeq}}{eq:b} $$ {{eqref: eq:a}} {{eqref: 1}}",
+            "b.md",
+            &[2],
+        ));
+        let pre = NumEqPreprocessor::new(&ctx);
+        let once = pre.run(&ctx, book).unwrap();
+        let twice = pre.run(&ctx, once.clone()).unwrap();
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn ref_with_caption() {
+        let chapters = || {
+            vec![chapter(
+                "A",
+                "$$ E = mc^2 {{numeq}}{eq:e}{Conservation of energy} $$ $$ {{numeq}}{eq:f} $$ \
+                 {{eqref: eq:e}} {{eqref: eq:f}}",
+                "a.md",
+                &[1],
+            )]
+        };
+        let contents = run_book("[preprocessor.numeq]\nref_with_caption = true", chapters());
+        assert_eq!(
+            contents[0],
+            "$$ E = mc^2 \\htmlId{eq:e}{} \\tag{1} $$ $$ \\htmlId{eq:f}{} \\tag{2} $$ \
+             [Conservation of energy (1)](#eq:e) [(2)](#eq:f)"
+        );
+        let contents = run_book("", chapters());
+        assert!(contents[0].ends_with("[(1)](#eq:e) [(2)](#eq:f)"));
+    }
+
+    #[test]
+    fn colon_replacement() {
+        let contents = run_book(
+            "[preprocessor.numeq]\ncolon_replacement = \"__\"",
+            vec![
+                chapter(
+                    "A",
+                    "$$ {{numeq}}{eq:sec:a} $$ {{eqref: eq:sec:a}}",
+                    "a.md",
+                    &[1],
+                ),
+                chapter("B", "{{eqref: eq:sec:a}}", "b.md", &[2]),
+            ],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq__sec__a}{} \\tag{1} $$ [(1)](#eq__sec__a)"
+        );
+        assert_eq!(contents[1], "[(1)](a.md#eq__sec__a)");
+    }
+
+    #[test]
+    fn cref() {
+        let content = "$$ {{numeq}}{a} $$ $$ {{numeq}}{b} $$ $$ {{numeq}}{c} $$\n\
+                       {{cref: a}}, {{cref: a, b}}, {{cref: a, b, c}}";
+        let contents = run_book("", vec![chapter("A", content, "a.md", &[1])]);
+        assert!(contents[0].ends_with(
+            "Equation [(1)](#a), Equations [(1)](#a) and [(2)](#b), \
+             Equations [(1)](#a), [(2)](#b) and [(3)](#c)"
+        ));
+    }
+
+    #[test]
+    fn cref_localized() {
+        let config = "[preprocessor.numeq]\n\
+                      cref_words = { singular = \"Équation\", plural = \"Équations\" }\n\
+                      list_conjunction = \"et\"";
+        let content = "$$ {{numeq}}{a} $$ $$ {{numeq}}{b} $$\n{{cref: a}}, {{cref: a, b}}";
+        let contents = run_book(config, vec![chapter("A", content, "a.md", &[1])]);
+        assert!(contents[0].ends_with("Équation [(1)](#a), Équations [(1)](#a) et [(2)](#b)"));
+    }
+
+    #[test]
+    fn ref_lists() {
+        let content = "$$ {{numeq}}{a} $$ $$ {{numeq}}{b} $$ $$ {{numeq}}{c} $$\n\
+                       {{eqref: a, b}}; {{eqref: a, b, c}}";
+        let chapters = || vec![chapter("A", content, "a.md", &[1])];
+        let contents = run_book("", chapters());
+        assert!(
+            contents[0].ends_with("[(1)](#a) and [(2)](#b); [(1)](#a), [(2)](#b) and [(3)](#c)")
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\noxford_comma = true\nlist_conjunction = \"&\"",
+            chapters(),
+        );
+        assert!(contents[0].ends_with("[(1)](#a) & [(2)](#b); [(1)](#a), [(2)](#b), & [(3)](#c)"));
+    }
+
+    #[test]
+    fn grouped_sub_chapters() {
+        let mut parent = chapter(
+            "Parent",
+            "<!-- numeq: group -->\n{{numeq}}",
+            "parent.md",
+            &[1],
+        );
+        let mut sub1 = chapter("Sub 1", "{{numeq}} {{numeq}}", "sub1.md", &[1, 1]);
+        sub1.parent_names = vec!["Parent".to_string()];
+        let mut sub2 = chapter("Sub 2", "{{numeq}}", "sub2.md", &[1, 2]);
+        sub2.parent_names = vec!["Parent".to_string()];
+        parent.sub_items = vec![BookItem::Chapter(sub1), BookItem::Chapter(sub2)];
+        let other = chapter("Other", "{{numeq}}", "other.md", &[2]);
+        let contents = run_book("[preprocessor.numeq]\nprefix = true", vec![parent, other]);
+        assert_eq!(
+            contents,
+            vec![
+                "\\tag{1.1}",
+                "\\tag{1.2} \\tag{1.3}",
+                "\\tag{1.4}",
+                "\\tag{2.1}"
+            ]
+        );
+    }
+
+    #[test]
+    fn self_refs() {
+        let content =
+            "$$\na = b {{numeq}}{eq:a} \\text{by {{eqref: eq:b}}, not {{eqref: eq:a}}}\n$$\n\n\
+                       $$ {{numeq}}{eq:b} $$\n\n\
+                       By {{eqref: eq:a}} and {{eqref: eq:b}}.";
+        assert_eq!(
+            NumEqPreprocessor::default().find_self_refs(content),
+            vec!["eq:a"]
+        );
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]\nwarn_self_ref = true",
+                vec![chapter("A", content, "a.md", &[1])],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Equation `eq:a' is referenced within its own block"]
+        );
+        let warnings = capture_warnings(|| {
+            run_book("", vec![chapter("A", content, "a.md", &[1])]);
+        });
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("Intro"), "intro");
+        assert_eq!(
+            slugify("  Groups & Fields: Basics "),
+            "groups-fields-basics"
+        );
+        assert_eq!(slugify("Théorie des Groupes"), "théorie-des-groupes");
+    }
+
+    #[test]
+    fn slug_prefix() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_source = \"slug\"\nglobal = true",
+            vec![
+                chapter("Intro", "{{numeq}} {{numeq}}", "intro.md", &[]),
+                chapter("Group theory", "{{numeq}}", "groups.md", &[]),
+                chapter("Intro", "{{numeq}}", "intro2.md", &[]),
+                chapter("Intro 2", "{{numeq}}", "intro3.md", &[]),
+                chapter("Intro", "{{numeq}}", "intro4.md", &[]),
+            ],
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "\\tag{intro:1} \\tag{intro:2}",
+                "\\tag{group-theory:1}",
+                "\\tag{intro-2:1}",
+                "\\tag{intro-2-2:1}",
+                "\\tag{intro-3:1}"
+            ]
+        );
+    }
+
+    #[test]
+    fn ref_format_with_section() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_format = \"(Eq. {n}, §{section})\"",
+            vec![
+                chapter("A", "$$ {{numeq}} {{numeq}}{eq:a} $$", "a.md", &[1, 2]),
+                chapter("B", "See {{eqref: eq:a}}.", "b.md", &[2]),
+            ],
+        );
+        assert_eq!(contents[1], "See [(Eq. 2, §1.2)](a.md#eq:a).");
+    }
+
+    #[test]
+    fn empty_label() {
+        let mut refs = HashMap::new();
+        let mut output = String::new();
+        let warnings = capture_warnings(|| {
+            output = NumEqPreprocessor::default().find_and_replace_eqs(
+                "{{numeq}}{}",
+                SECNUM,
+                "Groups",
+                &PATH,
+                &mut refs,
+                &mut Counters::default(),
+                &mut Vec::new(),
+            );
+        });
+        assert_eq!(output, "\\tag{1.2.1}");
+        assert!(refs.is_empty());
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"Groups\" (crypto/groups.md): Eq. 1.2.1: Empty label `{}' ignored"]
+        );
+    }
+
+    #[test]
+    fn huge_section_number() {
+        assert_eq!(parse_section_number("1.2.", "In a.md"), Some(vec![1, 2]));
+        let warnings = capture_warnings(|| {
+            assert_eq!(
+                parse_section_number("1.99999999999999999999999999.", "In a.md"),
+                None
+            );
+        });
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .starts_with("In a.md: Invalid section number `1.99999999999999999999999999.'"));
+    }
+
+    #[test]
+    fn counter_overflow() {
+        let mut ctr = Counters {
+            main: usize::MAX - 1,
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let warnings = capture_warnings(|| {
+            output = NumEqPreprocessor::default().find_and_replace_eqs(
+                "{{numeq}} {{numeq}}",
+                "",
+                "Groups",
+                &PATH,
+                &mut HashMap::new(),
+                &mut ctr,
+                &mut Vec::new(),
+            );
+        });
+        assert_eq!(ctr.main, usize::MAX);
+        assert_eq!(output, format!("\\tag{{{0}}} \\tag{{{0}}}", usize::MAX));
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn anchor_styles() {
+        let content = "$$ a {{numeq}}{eq:a} $$ {{eqref: eq:a}}";
+        let config = |style: &str| format!("[preprocessor.numeq]\nanchor_style = \"{style}\"");
+        let contents = run_book(&config("htmlid"), vec![chapter("A", content, "a.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "$$ a \\htmlId{eq:a}{} \\tag{1} $$ [(1)](#eq:a)"
+        );
+        let contents = run_book(&config("span"), vec![chapter("A", content, "a.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "<span id=\"eq:a\" class=\"numeq-anchor\"></span>$$ a \\tag{1} $$ [(1)](#eq:a)"
+        );
+        let contents = run_book(&config("aname"), vec![chapter("A", content, "a.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "<a name=\"eq:a\" class=\"numeq-anchor\"></a>$$ a \\tag{1} $$ [(1)](#eq:a)"
+        );
+    }
+
+    #[test]
+    fn anchor_class() {
+        let content = "$$ a {{numeq}}{eq:a} $$ {{eqref: eq:a}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nanchor_style = \"span\"\nanchor_class = \"my-eq-anchor\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "<span id=\"eq:a\" class=\"my-eq-anchor\"></span>$$ a \\tag{1} $$ [(1)](#eq:a)"
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nanchor_style = \"aname\"\nanchor_class = \"\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "<a name=\"eq:a\"></a>$$ a \\tag{1} $$ [(1)](#eq:a)"
+        );
+    }
+
+    #[test]
+    fn escaped_braces_in_labels() {
+        let content =
+            "$$ f(x) {{numeq}}{eq:f\\{x\\}} $$ $$ {{numeq}}{eq:\\{\\}}{A \\{caption\\}} $$\n\
+                       {{eqref: eq:f\\{x\\}}} {{eqref: eq:\\{\\}}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_with_caption = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ f(x) \\htmlId{eq:f-x-}{} \\tag{1} $$ $$ \\htmlId{eq:--}{} \\tag{2} $$\n\
+             [(1)](#eq:f-x-) [A {caption} (2)](#eq:--)"
+        );
+    }
+
+    #[test]
+    fn label_cache() {
+        let cache = std::env::temp_dir().join(format!("numeq-cache-{}.json", std::process::id()));
+        let mut cached = HashMap::new();
+        cached.insert(
+            "eq:old".to_string(),
+            LabelInfo {
+                num: "7".to_string(),
+                path: "a.md".into(),
+                ..Default::default()
+            },
+        );
+        cached.insert(
+            "eq:other".to_string(),
+            LabelInfo {
+                num: "3".to_string(),
+                path: "other.md".into(),
+                ..Default::default()
+            },
+        );
+        save_json(&cache, &cached, "label cache");
+        let config = format!(
+            "[preprocessor.numeq]\ncache_path = {:?}",
+            cache.display().to_string()
+        );
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                &config,
+                vec![chapter(
+                    "A",
+                    "$$ {{numeq}}{eq:new} $$ {{eqref: eq:other}} {{eqref: eq:old}} {{eqref: eq:new}}",
+                    "a.md",
+                    &[1],
+                )],
+            );
+            // the entry of the reprocessed chapter a.md is invalidated, and the chapter other.md
+            // is no longer in the book
+            assert_eq!(
+                contents[0],
+                "$$ \\htmlId{eq:new}{} \\tag{1} $$ **[??]** **[??]** [(1)](#eq:new)"
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "In chapter \"A\" (a.md): Unknown equation reference: eq:other",
+                "In chapter \"A\" (a.md): Unknown equation reference: eq:old"
+            ]
+        );
+        let saved = load_label_map(&cache);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved["eq:new"].num, "1");
+        // a single chapter processed afterwards resolves references to the other chapters from the cache
+        let pre = NumEqPreprocessor::new(&context(&config));
+        let (content, diagnostics) = pre.process_chapter(
+            "{{eqref: eq:new}} {{eqref: eq:other}}",
+            "",
+            Path::new("b.md"),
+        );
+        fs::remove_file(&cache).unwrap();
+        assert_eq!(content, "[(1)](a.md#eq:new) **[??]**");
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn summary() {
+        let ctx = context("[preprocessor.numeq]");
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$ {{eqref: eq:a, eq:nope}}",
+            "a.md",
+            &[1],
+        ));
+        book.push_item(chapter(
+            "B",
+            "$$ {{numeq}}{eq:a} $$ $$ {{numeq}}{eq:b} $$ {{cref: eq:b}} {{eqref: 5}}",
+            "b.md",
+            &[2],
+        ));
+        let (_, summary) = NumEqPreprocessor::new(&ctx)
+            .run_with_summary(&ctx, book)
+            .unwrap();
+        assert_eq!(
+            summary,
+            NumEqSummary {
+                total: 4,
+                labeled: 2,
+                references: 3,
+                unresolved: vec![
+                    ("eq:nope".to_string(), "a.md".into()),
+                    ("5".to_string(), "b.md".into())
+                ],
+                duplicates: vec![("eq:a".to_string(), "b.md".into())],
+                backrefs: BTreeMap::from([
+                    ("eq:a".to_string(), vec!["a.md".into()]),
+                    ("eq:b".to_string(), vec!["b.md".into()])
+                ]),
+                labels: HashMap::from([
+                    (
+                        "eq:a".to_string(),
+                        LabelInfo {
+                            num: "1".to_string(),
+                            path: "a.md".into(),
+                            section: Some("1".to_string()),
+                            title: Some("A".to_string()),
+                            ..Default::default()
+                        }
+                    ),
+                    (
+                        "eq:b".to_string(),
+                        LabelInfo {
+                            num: "2".to_string(),
+                            path: "b.md".into(),
+                            section: Some("2".to_string()),
+                            title: Some("B".to_string()),
+                            ..Default::default()
+                        }
+                    ),
+                ]),
+            }
+        );
+    }
+
+    #[test]
+    fn manual_tags() {
+        let content = "$$ a \\tag{A} $$ $$ b {{numeq}}{eq:b} $$ {{eqref: eq:b}}";
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]\nskip_manual_tags = true",
+                vec![
+                    chapter("A", content, "a.md", &[1]),
+                    chapter("B", "$$ {{numeq}} $$", "b.md", &[2]),
+                ],
+            );
+            // the markers of the skipped chapter are kept as is and its labels are unknown
+            assert_eq!(
+                contents,
+                vec![
+                    "$$ a \\tag{A} $$ $$ b {{numeq}}{eq:b} $$ **[??]**",
+                    "$$ \\tag{1} $$"
+                ]
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Unknown equation reference: eq:b"]
+        );
+
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]",
+                vec![chapter("A", content, "a.md", &[1])],
+            );
+            assert_eq!(
+                contents[0],
+                "$$ a \\tag{A} $$ $$ b \\htmlId{eq:b}{} \\tag{1} $$ [(1)](#eq:b)"
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Both {{numeq}} markers and manual \\tag commands found, numbers may be inconsistent"]
+        );
+    }
+
+    #[test]
+    fn table_inline_numbers() {
+        let content = "| a | b |\n|---|---|\n| $$ x {{numeq}}{eq:x} $$ | $$ y {{numeq}} $$ |\n\n$$ z {{numeq}} $$";
+        let contents = run_book(
+            "[preprocessor.numeq]\ntable_inline_numbers = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "| a | b |\n|---|---|\n| $$ x \\htmlId{eq:x}{} $$ (1) | $$ y  $$ (2) |\n\n$$ z \\tag{3} $$"
+        );
+    }
+
+    #[test]
+    fn backref_map() {
+        let map = std::env::temp_dir().join(format!("numeq-backrefs-{}.json", std::process::id()));
+        let config = format!(
+            "[preprocessor.numeq]\nbackref_map = {:?}",
+            map.display().to_string()
+        );
+        run_book(
+            &config,
+            vec![
+                chapter("A", "$$ {{numeq}}{eq:a} $$ {{eqref: 1}}", "a.md", &[1]),
+                chapter("B", "{{eqref: eq:a}} {{cref: eq:a, eq:b}}", "b.md", &[2]),
+                chapter("C", "$$ {{numeq}}{eq:b} $$ {{eqref: eq:a}}", "c.md", &[3]),
+            ],
+        );
+        let backrefs: BTreeMap<String, Vec<PathBuf>> =
+            serde_json::from_str(&fs::read_to_string(&map).unwrap()).unwrap();
+        fs::remove_file(&map).unwrap();
+        assert_eq!(
+            backrefs,
+            BTreeMap::from([
+                (
+                    "eq:a".to_string(),
+                    vec!["a.md".into(), "b.md".into(), "c.md".into()]
+                ),
+                ("eq:b".to_string(), vec!["b.md".into()]),
+            ])
+        );
+    }
+
+    #[test]
+    fn chapter_code() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true",
+            vec![
+                chapter(
+                    "Thermodynamics",
+                    "<!-- numeq: tag=TH -->\n$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$",
+                    "th.md",
+                    &[1],
+                ),
+                chapter(
+                    "Mechanics",
+                    "<!-- numeq: tag=ME -->\n$$ {{numeq}} $$ {{eqref: eq:a}}",
+                    "me.md",
+                    &[2],
+                ),
+                chapter("Other", "$$ {{numeq}} $$", "other.md", &[3]),
+            ],
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\htmlId{eq:a}{} \\tag{TH.1} $$ $$ \\tag{TH.2} $$",
+                "$$ \\tag{ME.1} $$ [(TH.1)](th.md#eq:a)",
+                "$$ \\tag{3.1} $$"
+            ]
+        );
+    }
+
+    #[test]
+    fn footnote_refs() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_style = \"footnote\"",
+            vec![
+                chapter("A", "$$ {{numeq}}{eq:a} $$", "a.md", &[1]),
+                chapter(
+                    "B",
+                    "$$ {{numeq}}{eq:b} $$ See {{eqref: eq:a}} and {{cref: 1, eq:a}}.",
+                    "b.md",
+                    &[2],
+                ),
+            ],
+        );
+        assert_eq!(contents[0], "$$ \\htmlId{eq:a}{} \\tag{1} $$");
+        assert_eq!(
+            contents[1],
+            "$$ \\htmlId{eq:b}{} \\tag{1} $$ See [^numeq-eq:a] and Equations [^numeq-eq:b] and [^numeq-eq:a].\n\n\
+             [^numeq-eq:a]: [(1)](a.md#eq:a)\n\n\
+             [^numeq-eq:b]: [(1)](#eq:b)\n"
+        );
+    }
+
+    #[test]
+    fn require_labels() {
+        let chapters = || {
+            vec![chapter(
+                "A",
+                "$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$",
+                "a.md",
+                &[1],
+            )]
+        };
+        let warnings = capture_warnings(|| {
+            run_book("[preprocessor.numeq]\nrequire_labels = true", chapters());
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Eq. 2: Missing label"]
+        );
+
+        let ctx = context("[preprocessor.numeq]\nrequire_labels = true\nstrict = true");
+        let mut book = Book::new();
+        for ch in chapters() {
+            book.push_item(ch);
+        }
+        let err = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "In chapter \"A\" (a.md): Eq. 2: Missing label"
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        let content = "$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$ $$ {{numeq}}{eq:c} $$ {{eqref: eq:a..eq:c}} {{cref: eq:a .. 2}}";
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert!(contents[0]
+            .ends_with(" [(1)](#eq:a)\u{2013}[(3)](#eq:c) Equations [(1)](#eq:a)\u{2013}(2)"));
+
+        let contents = run_book(
+            "[preprocessor.numeq]\nrange_separator = \"-\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert!(contents[0].ends_with(" [(1)](#eq:a)-[(3)](#eq:c) Equations [(1)](#eq:a)-(2)"));
+    }
+
+    #[test]
+    fn reset_modes() {
+        let numbers = |config: &str| {
+            let ctx = context(config);
+            let mut book = Book::new();
+            let mut ch1 = chapter("A", "$$ {{numeq}} $$ $$ {{numeq}} $$", "a.md", &[1]);
+            ch1.sub_items.push(BookItem::Chapter(chapter(
+                "A.1",
+                "$$ {{numeq}} $$",
+                "a1.md",
+                &[1, 1],
+            )));
+            book.push_item(ch1);
+            book.push_item(BookItem::PartTitle("Part II".to_string()));
+            book.push_item(chapter("B", "$$ {{numeq}} $$", "b.md", &[2]));
+            let book = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+            book.iter()
+                .filter_map(|item| match item {
+                    BookItem::Chapter(ch) => Some(ch.content.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(
+            numbers("[preprocessor.numeq]\nreset = \"never\""),
+            "$$ \\tag{1} $$ $$ \\tag{2} $$ $$ \\tag{3} $$ $$ \\tag{4} $$"
+        );
+        assert_eq!(
+            numbers("[preprocessor.numeq]\nreset = \"chapter\"\nglobal = true"),
+            "$$ \\tag{1} $$ $$ \\tag{2} $$ $$ \\tag{1} $$ $$ \\tag{1} $$"
+        );
+        assert_eq!(
+            numbers("[preprocessor.numeq]\nreset = \"part\""),
+            "$$ \\tag{1} $$ $$ \\tag{2} $$ $$ \\tag{3} $$ $$ \\tag{1} $$"
+        );
+        assert_eq!(
+            numbers("[preprocessor.numeq]\nreset = \"depth\"\nprefix = true\ndepth = 1"),
+            "$$ \\tag{1.1} $$ $$ \\tag{1.2} $$ $$ \\tag{1.3} $$ $$ \\tag{2.1} $$"
+        );
+        // the counter never resets, whatever the prefix
+        assert_eq!(
+            numbers("[preprocessor.numeq]\nreset = \"never\"\nprefix = true\ndepth = 1"),
+            "$$ \\tag{1.1} $$ $$ \\tag{1.2} $$ $$ \\tag{1.3} $$ $$ \\tag{2.4} $$"
+        );
+    }
+
+    #[test]
+    fn prefix_trailing_dot() {
+        let chapters = || {
+            let mut ch = chapter("A", "$$ {{numeq}}{eq:a} $$", "a.md", &[1]);
+            ch.sub_items.push(BookItem::Chapter(chapter(
+                "B",
+                "$$ {{numeq}} $$ {{eqref: eq:a}}",
+                "b.md",
+                &[1, 2],
+            )));
+            vec![ch]
+        };
+        let contents = run_book("[preprocessor.numeq]\nprefix = true", chapters());
+        assert_eq!(contents[0], "$$ \\htmlId{eq:a}{} \\tag{1.1} $$");
+        assert_eq!(contents[1], "$$ \\tag{1.2.1} $$ [(1.1)](a.md#eq:a)");
+
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_trailing_dot = false",
+            chapters(),
+        );
+        assert_eq!(contents[0], "$$ \\htmlId{eq:a}{} \\tag{1(1)} $$");
+        assert_eq!(contents[1], "$$ \\tag{1.2(1)} $$ [(1(1))](a.md#eq:a)");
+        // ranges of parenthesized numbers
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_trailing_dot = false",
+            vec![chapter(
+                "A",
+                "$$ {{numeq}} $$ $$ {{numeq}} $$ $$ {{numeq}} $$ {{cref: 1..3}}",
+                "a.md",
+                &[1],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\tag{1(1)} $$ $$ \\tag{1(2)} $$ $$ \\tag{1(3)} $$ Equations (1(1))–(1(3))"
+        );
+    }
+
+    #[test]
+    fn mathjax_native_refs() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nmathjax_native_refs = true\nanchor_style = \"span\"",
+            vec![
+                chapter(
+                    "A",
+                    "$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$ {{eqref: eq:a}} {{cref: 1, 2}}",
+                    "a.md",
+                    &[1],
+                ),
+                chapter("B", "{{eqref: eq:a}}", "b.md", &[2]),
+            ],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\label{eq:a} \\tag{1} $$ $$ \\tag{2} $$ \\eqref{eq:a} Equations \\eqref{eq:a} and (2)"
+        );
+        // references across chapters are still links
+        assert_eq!(contents[1], "[(1)](a.md#mjx-eqn-eq:a)");
+    }
+
+    #[test]
+    fn show_and_reset_depths() {
+        let numbers = |config: &str| {
+            let mut ch1 = chapter("A", "$$ {{numeq}} $$", "a.md", &[1]);
+            let mut ch11 = chapter("A.1", "$$ {{numeq}} $$", "a1.md", &[1, 1]);
+            ch11.sub_items.push(BookItem::Chapter(chapter(
+                "A.1.1",
+                "$$ {{numeq}} $$",
+                "a11.md",
+                &[1, 1, 1],
+            )));
+            ch1.sub_items.push(BookItem::Chapter(ch11));
+            ch1.sub_items.push(BookItem::Chapter(chapter(
+                "A.2",
+                "$$ {{numeq}} $$",
+                "a2.md",
+                &[1, 2],
+            )));
+            let ch2 = chapter("B", "$$ {{numeq}} $$", "b.md", &[2]);
+            run_book(
+                &format!("[preprocessor.numeq]\nprefix = true\n{config}"),
+                vec![ch1, ch2],
+            )
+            .iter()
+            .map(|c| {
+                c.trim_start_matches("$$ \\tag{")
+                    .trim_end_matches("} $$")
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+        };
+        // the old depth option sets both depths
+        assert_eq!(
+            numbers("depth = 1"),
+            vec!["1.1", "1.2", "1.3", "1.4", "2.1"]
+        );
+        assert_eq!(
+            numbers("show_depth = 1\nreset_depth = 1"),
+            vec!["1.1", "1.2", "1.3", "1.4", "2.1"]
+        );
+        assert_eq!(
+            numbers("show_depth = 1\nreset_depth = 2"),
+            vec!["1.1", "1.1", "1.2", "1.1", "2.1"]
+        );
+        assert_eq!(
+            numbers("show_depth = 2\nreset_depth = 1"),
+            vec!["1.0.1", "1.1.2", "1.1.3", "1.2.4", "2.0.1"]
+        );
+        assert_eq!(
+            numbers("reset_depth = 1"),
+            vec!["1.1", "1.1.2", "1.1.1.3", "1.2.4", "2.1"]
+        );
+        assert_eq!(
+            numbers("show_depth = 2\nreset_depth = 3"),
+            vec!["1.0.1", "1.1.1", "1.1.1", "1.2.1", "2.0.1"]
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let ctx = context("[preprocessor.numeq]");
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "$$ {{numeq}}{eq:a}[kind=lemma, src = paper2020] $$ $$ {{numeq}}{eq:b}{Caption}[kind=def,oops] $$ {{eqref: eq:a}}",
+            "a.md",
+            &[1],
+        ));
+        let mut result = None;
+        let warnings = capture_warnings(|| {
+            result = Some(
+                NumEqPreprocessor::new(&ctx)
+                    .run_with_summary(&ctx, book)
+                    .unwrap(),
+            );
+        });
+        let (book, summary) = result.unwrap();
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Eq. 2: Ignoring metadata `oops' which is not of the form key=value"]
+        );
+        let BookItem::Chapter(ch) = &book.sections[0] else {
+            panic!("expected a chapter");
+        };
+        assert_eq!(
+            ch.content,
+            "$$ \\htmlId{eq:a}{} \\tag{1} $$ $$ \\htmlId{eq:b}{} \\tag{2} $$ [(1)](#eq:a)"
+        );
+        assert_eq!(
+            summary.labels["eq:a"].meta,
+            HashMap::from([
+                ("kind".to_string(), "lemma".to_string()),
+                ("src".to_string(), "paper2020".to_string())
+            ])
+        );
+        assert_eq!(
+            summary.labels["eq:b"].meta,
+            HashMap::from([("kind".to_string(), "def".to_string())])
+        );
+        assert_eq!(summary.labels["eq:b"].caption.as_deref(), Some("Caption"));
+    }
+
+    #[test]
+    fn a11y() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\na11y = true\nanchor_style = \"span\"",
+            vec![chapter(
+                "A",
+                "$$ {{numeq}}{eq:a} $$\n\n$$ {{numeq}} $$",
+                "a.md",
+                &[1, 2],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "<span id=\"eq:a\" class=\"numeq-anchor\"></span><span class=\"sr-only\">Equation 1.2.1</span>$$ \\tag{1.2.1} $$\n\n\
+             <span class=\"sr-only\">Equation 1.2.2</span>$$ \\tag{1.2.2} $$"
+        );
+    }
+
+    #[test]
+    fn anchor_offset() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nanchor_offset = 3",
+            vec![chapter(
+                "A",
+                "Text.\n\n$$\na \\\\\nb {{numeq}}{eq:a}\n$$ {{eqref: eq:a}}",
+                "a.md",
+                &[1],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "Text.\n\n<span id=\"eq:a\" style=\"display: block; position: relative; top: -3em\"></span>$$\na \\\\\nb \\tag{1}\n$$ [(1)](#eq:a)"
+        );
+    }
+
+    #[test]
+    fn link_text_override() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_style = \"footnote\"",
+            vec![
+                chapter(
+                    "A",
+                    "$$ {{numeq}}{eq:x} $$ $$ {{numeq}} $$ as shown {{eqref: eq:x|above}}, {{eqref: 2 | below}}",
+                    "a.md",
+                    &[1],
+                ),
+                chapter("B", "see {{cref: eq:x| the first one}}", "b.md", &[2]),
+            ],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:x}{} \\tag{1} $$ $$ \\tag{2} $$ as shown [above](#eq:x), below"
+        );
+        assert_eq!(contents[1], "see Equation [the first one](a.md#eq:x)");
+    }
+
+    #[test]
+    fn idempotent_passes() {
+        let content = "| $$ {{numeq}} $$ |\n\n$$ a {{numeq}}{eq:a} $$ $$ b \\tag{B} $$ {{eqref: eq:a}} {{cref: 1..2}}";
+        for config in [
+            "",
+            "output = \"data-attr\"",
+            "anchor_style = \"span\"\na11y = true\ntable_inline_numbers = true",
+            "ref_style = \"footnote\"\nmathjax_native_refs = true",
+        ] {
+            let pre = NumEqPreprocessor::new(&context(&format!("[preprocessor.numeq]\n{config}")));
+            let mut refs = HashMap::new();
+            let mut ctr = Counters::default();
+            let mut eqs = Vec::new();
+            let once = pre
+                .find_and_replace_eqs(content, "", "Groups", &PATH, &mut refs, &mut ctr, &mut eqs);
+            let once = pre.find_and_replace_refs(
+                &once,
+                "Groups",
+                &PATH,
+                &refs,
+                &eqs,
+                &mut NumEqSummary::default(),
+            );
+            let warnings = capture_warnings(|| {
+                let mut eqs_again = Vec::new();
+                let twice = pre.find_and_replace_eqs(
+                    &once,
+                    "",
+                    "Groups",
+                    &PATH,
+                    &mut refs,
+                    &mut ctr,
+                    &mut eqs_again,
+                );
+                let twice = pre.find_and_replace_refs(
+                    &twice,
+                    "Groups",
+                    &PATH,
+                    &refs,
+                    &eqs_again,
+                    &mut NumEqSummary::default(),
+                );
+                assert_eq!(once, twice, "with config {config:?}");
+                assert!(eqs_again.is_empty());
+            });
+            assert!(warnings.is_empty(), "with config {config:?}: {warnings:?}");
+            assert_eq!(ctr.main, 2);
+        }
+    }
+
+    #[test]
+    fn line_annotations() {
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter(
+                "A",
+                "$$ a \\\\ b {{numeq}}{eq:a} $$ $$ c {{numeq}} $$ {{eqref: eq:a, line=2}} {{cref: eq:a, line = 1, 2, line=3}} {{eqref: line=1}}",
+                "a.md",
+                &[1],
+            )],
+        );
+        assert!(contents[0]
+            .ends_with(" [(1), line 2](#eq:a) Equations [(1), line 1](#eq:a) and (2), line 3 "));
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]",
+                vec![chapter("A", "{{eqref: line=1}}", "a.md", &[1])],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Ignoring `line=1' not preceded by a reference"]
+        );
+    }
+
+    #[test]
+    fn appendices() {
+        let mut intro = chapter("Intro", "$$ {{numeq}} $$", "intro.md", &[]);
+        intro.number = None;
+        let mut appendix_a = chapter("A", "$$ {{numeq}} $$ $$ {{numeq}}{eq:a} $$", "a.md", &[]);
+        appendix_a.number = None;
+        let mut appendix_b = chapter("B", "$$ {{numeq}} $$ {{eqref: eq:a}}", "b.md", &[]);
+        appendix_b.number = None;
+        let chapters = || {
+            vec![
+                intro.clone(),
+                chapter("One", "$$ {{numeq}} $$ $$ {{numeq}} $$", "one.md", &[1]),
+                chapter("Two", "$$ {{numeq}} $$", "two.md", &[2]),
+                appendix_a.clone(),
+                appendix_b.clone(),
+            ]
+        };
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nappendices = true",
+            chapters(),
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\tag{1} $$",
+                "$$ \\tag{1.1} $$ $$ \\tag{1.2} $$",
+                "$$ \\tag{2.1} $$",
+                "$$ \\tag{A.1} $$ $$ \\htmlId{eq:a}{} \\tag{A.2} $$",
+                "$$ \\tag{B.1} $$ [(A.2)](a.md#eq:a)",
+            ]
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nglobal = true\nappendices = true",
+            chapters(),
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\tag{1} $$",
+                "$$ \\tag{2} $$ $$ \\tag{3} $$",
+                "$$ \\tag{4} $$",
+                "$$ \\tag{1} $$ $$ \\htmlId{eq:a}{} \\tag{2} $$",
+                "$$ \\tag{1} $$ [(2)](a.md#eq:a)",
+            ]
+        );
+        assert_eq!(appendix_letter(1), "A");
+        assert_eq!(appendix_letter(26), "Z");
+        assert_eq!(appendix_letter(28), "AB");
+    }
+
+    #[test]
+    fn ordered_traversal_depths() {
+        let mut ch1 = chapter("1", "", "1.md", &[1]);
+        let mut ch11 = chapter("1.1", "", "11.md", &[1, 1]);
+        ch11.sub_items.push(BookItem::Chapter(chapter(
+            "1.1.1",
+            "",
+            "111.md",
+            &[1, 1, 1],
+        )));
+        ch1.sub_items.push(BookItem::Chapter(ch11));
+        ch1.sub_items
+            .push(BookItem::Chapter(chapter("1.2", "", "12.md", &[1, 2])));
+        let mut book = Book::new();
+        book.push_item(ch1);
+        book.push_item(BookItem::Separator);
+        book.push_item(chapter("2", "", "2.md", &[2]));
+        let mut visited = Vec::new();
+        for_each_mut_ordered_with_depth(
+            &mut |item: &mut BookItem, depth| match item {
+                BookItem::Chapter(ch) => visited.push((ch.name.clone(), depth)),
+                _ => visited.push(("-".to_string(), depth)),
+            },
+            &mut book.sections,
+        );
+        assert_eq!(
+            visited,
+            vec![
+                ("1".to_string(), 0),
+                ("1.1".to_string(), 1),
+                ("1.1.1".to_string(), 2),
+                ("1.2".to_string(), 1),
+                ("-".to_string(), 0),
+                ("2".to_string(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_label_warning() {
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]\nprefix = true",
+                vec![
+                    chapter("A", "$$ {{numeq}} $$ $$ {{numeq}}{eq:a} $$", "a.md", &[1]),
+                    chapter("B", "$$ {{numeq}}{eq:a} $$ {{eqref: eq:a}}", "b.md", &[2]),
+                ],
+            );
+            // references point to the first equation
+            assert!(contents[1].ends_with(" [(1.2)](a.md#eq:a)"));
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"B\" (b.md): Eq. 2.1: Label `eq:a' already used for Eq. 1.2 in a.md"]
+        );
+    }
+
+    #[test]
+    fn families() {
+        let content = "$$ {{numeq}} $$ $$ {{numeq:family=f1}}{eq:a} $$ text $$ {{numeq}} $$ $$ {{numeq:family=f1}} $$ \
+                       {{numeq:endfamily}} $$ {{numeq:family=f1}} $$ $$ {{numeq:family=f2}} $$ {{eqref: eq:a}}";
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]",
+                vec![chapter(
+                    "A",
+                    &format!("{content} {{{{numeq:endfamily}}}}"),
+                    "a.md",
+                    &[1],
+                )],
+            );
+            assert_eq!(
+                contents[0],
+                "$$ \\tag{1} $$ $$ \\htmlId{eq:a}{} \\tag{2a} $$ text $$ \\tag{3} $$ $$ \\tag{2b} $$  \
+                 $$ \\tag{4a} $$ $$ \\tag{5a} $$ [(2a)](#eq:a) "
+            );
+        });
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]",
+                vec![chapter(
+                    "A",
+                    "{{numeq:endfamily}} $$ {{numeq:foo}}{eq:foo} $$",
+                    "a.md",
+                    &[1],
+                )],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "In chapter \"A\" (a.md): Ignoring `{{numeq:endfamily}}' without open family",
+                "In chapter \"A\" (a.md): Unknown marker argument `foo', numbering the equation as usual"
+            ]
+        );
+    }
+
+    #[test]
+    fn searchable() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nsearchable = true",
+            vec![chapter(
+                "A",
+                "$$ {{numeq}}{eq:a} $$\n\n$$ {{numeq}} $$",
+                "a.md",
+                &[1, 2],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "<span hidden>Equation 1.2.1</span>$$ \\htmlId{eq:a}{} \\tag{1.2.1} $$\n\n\
+             <span hidden>Equation 1.2.2</span>$$ \\tag{1.2.2} $$"
+        );
+    }
+
+    #[test]
+    fn local_and_remote_ref_formats() {
+        let chapters = || {
+            vec![
+                chapter(
+                    "A",
+                    "$$ {{numeq}} $$ $$ {{numeq}}{eq:a} $$ {{eqref: eq:a}} {{eqref: 1}}",
+                    "a.md",
+                    &[2],
+                ),
+                chapter("B", "{{eqref: eq:a}}", "b.md", &[3]),
+            ]
+        };
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_format_remote = \"(Ch. {section}, Eq. {n})\"",
+            chapters(),
+        );
+        assert!(contents[0].ends_with(" [(2)](#eq:a) (1)"));
+        assert_eq!(contents[1], "[(Ch. 2, Eq. 2)](a.md#eq:a)");
+
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_format = \"Eq. {n}\"\nref_format_local = \"{n}\"",
+            chapters(),
+        );
+        assert!(contents[0].ends_with(" [2](#eq:a) 1"));
+        assert_eq!(contents[1], "[Eq. 2](a.md#eq:a)");
+    }
+
+    #[test]
+    fn duplicate_paths() {
+        let chapters = || {
+            vec![
+                chapter("A", "$$ {{numeq}} $$", "a.md", &[1]),
+                chapter("B", "$$ {{numeq}} $$", "a.md", &[2]),
+            ]
+        };
+        let warnings = capture_warnings(|| {
+            run_book("[preprocessor.numeq]", chapters());
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"B\" (a.md): Chapter `A' has the same path, references to the equations of both chapters are ambiguous"]
+        );
+
+        let ctx = context("[preprocessor.numeq]\nstrict = true");
+        let mut book = Book::new();
+        for ch in chapters() {
+            book.push_item(ch);
+        }
+        let err = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "In chapter \"B\" (a.md): Chapter `A' has the same path, references to the equations of both chapters are ambiguous"
+        );
+    }
+
+    #[test]
+    fn number_above() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nnumber_position = \"above\"",
+            vec![chapter(
+                "A",
+                "Text.\n\n$$\nx {{numeq}}{eq:x}\n$$\n\n$$ y {{numeq}} $$\n\n{{eqref: eq:x}}",
+                "a.md",
+                &[1, 2],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "Text.\n\n<span id=\"eq:x\"></span>**(1.2.1)**\n\n$$\nx \n$$\n\n**(1.2.2)**\n\n$$ y  $$\n\n[(1.2.1)](#eq:x)"
+        );
+    }
+
+    #[test]
+    fn bare_markers() {
+        let content = "$$ a {{numeq}} $$ $$ b {{numeq}}{eq:b} $$ $$ c {{numeq}}{} $$ {{eqref: eq:b}} {{eqref: 2}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nbare = \"number\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\tag{1} $$ $$ b \\htmlId{eq:b}{} \\tag{2} $$ $$ c \\tag{3} $$ [(2)](#eq:b) [(2)](#eq:b)"
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nbare = \"ignore\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a {{numeq}} $$ $$ b \\htmlId{eq:b}{} \\tag{1} $$ $$ c {{numeq}}{} $$ [(1)](#eq:b) **[??]**"
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nbare = \"remove\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a  $$ $$ b \\htmlId{eq:b}{} \\tag{1} $$ $$ c  $$ [(1)](#eq:b) **[??]**"
+        );
+    }
+
+    #[test]
+    fn max_label_len() {
+        let content = "$$ a {{numeq}}{eq:a-very-long-label} $$ $$ b {{numeq}}{eq:short} $$ {{eqref: eq:a-very-long-label}} {{eqref: eq:short}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nmax_label_len = 12",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        let truncated = NumEqPreprocessor {
+            max_label_len: Some(12),
+            ..Default::default()
+        }
+        .shorten_label("eq:a-very-long-label".to_string());
+        assert_eq!(truncated.chars().count(), 12);
+        assert!(truncated.starts_with("eq:-"));
+        assert_eq!(
+            contents[0],
+            format!(
+                "$$ a \\htmlId{{{truncated}}}{{}} \\tag{{1}} $$ $$ b \\htmlId{{eq:short}}{{}} \\tag{{2}} $$ [(1)](#{truncated}) [(2)](#eq:short)"
+            )
+        );
+        let ctx = context("[preprocessor.numeq]\nmax_label_len = 12\nstrict = true");
+        let mut book = Book::new();
+        book.push_item(chapter("A", content, "a.md", &[1]));
+        assert!(NumEqPreprocessor::new(&ctx).run(&ctx, book).is_err());
+    }
+
+    #[test]
+    fn html_div_blocks() {
+        let content = "<div class=\"math\">\n$$ a {{numeq}}{eq:a} $$\n</div>\n\n<div class=\"math\">$$ b {{numeq}}{eq:b} $$</div>\n\n{{eqref: eq:a}} {{eqref: eq:b}}";
+        let config = |style: &str| format!("[preprocessor.numeq]\nanchor_style = \"{style}\"");
+        let contents = run_book(&config("htmlid"), vec![chapter("A", content, "a.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "<div class=\"math\">\n$$ a \\htmlId{eq:a}{} \\tag{1} $$\n</div>\n\n<div class=\"math\">$$ b \\htmlId{eq:b}{} \\tag{2} $$</div>\n\n[(1)](#eq:a) [(2)](#eq:b)"
+        );
+        // the anchor is placed right before the math block, hence inside the enclosing element
+        let contents = run_book(&config("span"), vec![chapter("A", content, "a.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "<div class=\"math\">\n<span id=\"eq:a\" class=\"numeq-anchor\"></span>$$ a \\tag{1} $$\n</div>\n\n<div class=\"math\"><span id=\"eq:b\" class=\"numeq-anchor\"></span>$$ b \\tag{2} $$</div>\n\n[(1)](#eq:a) [(2)](#eq:b)"
+        );
+        let contents = run_book(&config("aname"), vec![chapter("A", content, "a.md", &[1])]);
+        assert_eq!(
+            contents[0],
+            "<div class=\"math\">\n<a name=\"eq:a\" class=\"numeq-anchor\"></a>$$ a \\tag{1} $$\n</div>\n\n<div class=\"math\"><a name=\"eq:b\" class=\"numeq-anchor\"></a>$$ b \\tag{2} $$</div>\n\n[(1)](#eq:a) [(2)](#eq:b)"
+        );
+        // no blank line is inserted within the HTML block of the second equation
+        let contents = run_book(
+            "[preprocessor.numeq]\nnumber_position = \"above\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "<div class=\"math\">\n<span id=\"eq:a\"></span>**(1)**\n\n$$ a  $$\n</div>\n\n<div class=\"math\"><span id=\"eq:b\"></span><strong>(2)</strong><br>$$ b  $$</div>\n\n[(1)](#eq:a) [(2)](#eq:b)"
+        );
+    }
+
+    #[test]
+    fn localized_ref_format() {
+        let config = |language: &str| {
+            format!(
+                "[book]\nlanguage = \"{language}\"\n\n[preprocessor.numeq.ref_format]\nen = \"Eq. ({{n}})\"\nfr = \"Éq. ({{n}})\"\nde = \"Gl. ({{n}})\""
+            )
+        };
+        let content = "$$ {{numeq}}{eq:a} $$ {{eqref: eq:a}}";
+        for (language, text) in [
+            ("en", "Eq. (1)"),
+            ("fr", "Éq. (1)"),
+            ("de", "Gl. (1)"),
+            ("it", "(1)"),
+        ] {
+            let contents = run_book(&config(language), vec![chapter("A", content, "a.md", &[1])]);
+            assert_eq!(
+                contents[0],
+                format!("$$ \\htmlId{{eq:a}}{{}} \\tag{{1}} $$ [{text}](#eq:a)")
+            );
+        }
+    }
+
+    #[test]
+    fn external_refs() {
+        let external = || {
+            HashMap::from([(
+                "ext:a".to_string(),
+                LabelInfo {
+                    num: "7".to_string(),
+                    path: PathBuf::from("other/b.md"),
+                    ..Default::default()
+                },
+            )])
+        };
+        let content = "$$ {{numeq}}{ext:a} $$ {{eqref: ext:a}}";
+        let run = |config: &str| {
+            let ctx = context(config);
+            let mut book = Book::new();
+            book.push_item(chapter("A", content, "a.md", &[1]));
+            let book = NumEqPreprocessor::new(&ctx)
+                .with_external_refs(external())
+                .run(&ctx, book)
+                .unwrap();
+            match &book.sections[0] {
+                BookItem::Chapter(ch) => ch.content.clone(),
+                _ => unreachable!(),
+            }
+        };
+        // the external definition takes precedence by default
+        assert_eq!(
+            run("[preprocessor.numeq]"),
+            "$$ \\htmlId{ext:a}{} \\tag{1} $$ [(7)](other/b.md#ext:a)"
+        );
+        assert_eq!(
+            run("[preprocessor.numeq]\noverride_external = true"),
+            "$$ \\htmlId{ext:a}{} \\tag{1} $$ [(1)](#ext:a)"
+        );
+    }
+
+    #[test]
+    fn index_prefixes() {
+        let chapters = || {
+            vec![
+                chapter("Intro", "$$ {{numeq}} $$", "intro.md", &[]),
+                chapter("A", "$$ {{numeq}} $$ $$ {{numeq}} $$", "a.md", &[3]),
+                chapter("B", "$$ {{numeq}} $$", "b.md", &[5]),
+            ]
+        };
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_source = \"section\"",
+            chapters(),
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\tag{1} $$",
+                "$$ \\tag{3.1} $$ $$ \\tag{3.2} $$",
+                "$$ \\tag{5.1} $$"
+            ]
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_source = \"index\"",
+            chapters(),
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\tag{1.1} $$",
+                "$$ \\tag{2.1} $$ $$ \\tag{2.2} $$",
+                "$$ \\tag{3.1} $$"
+            ]
+        );
+    }
+
+    #[test]
+    fn single_separator() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\ndepth = 1",
+            vec![
+                chapter("A", "$$ {{numeq}} $$", "a.md", &[3]),
+                chapter("B", "<!-- numeq: tag=B. -->\n$$ {{numeq}} $$", "b.md", &[4]),
+                chapter(
+                    "C",
+                    "<!-- numeq: tag=C.. -->\n$$ {{numeq}} $$",
+                    "c.md",
+                    &[5],
+                ),
+            ],
+        );
+        assert_eq!(
+            contents,
+            vec!["$$ \\tag{3.1} $$", "$$ \\tag{B.1} $$", "$$ \\tag{C.1} $$"]
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_trailing_dot = false",
+            vec![chapter(
+                "B",
+                "<!-- numeq: tag=B. -->\n$$ {{numeq}} $$",
+                "b.md",
+                &[4],
+            )],
+        );
+        assert_eq!(contents, vec!["$$ \\tag{B(1)} $$"]);
+    }
+
+    #[test]
+    fn wrap_block() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nwrap_block = true",
+            vec![chapter(
+                "A",
+                "$$ a {{numeq}}{eq:a} $$\n\n$$\nb {{numeq}} \\\\\nc {{numeq}}{eq:c}\n$$\n\n{{eqref: eq:a}} {{eqref: eq:c}}",
+                "a.md",
+                &[1],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "<div class=\"equation\" id=\"eq:a\">\n\n$$ a \\tag{1} $$\n\n</div>\n\n\
+            <div class=\"equation\">\n\n$$\nb \\tag{2} \\\\\nc \\htmlId{eq:c}{} \\tag{3}\n$$\n\n</div>\n\n\
+            [(1)](#eq:a) [(3)](#eq:c)"
+        );
+    }
+
+    #[test]
+    fn skip_comments() {
+        let content = "$$ a {{numeq}} $$\n<!-- $$ draft {{numeq}}{eq:d} $$ -->\n$$ b {{numeq}}{eq:b} $$ {{eqref: eq:b}}";
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\tag{1} $$\n<!-- $$ draft {{numeq}}{eq:d} $$ -->\n$$ b \\htmlId{eq:b}{} \\tag{2} $$ [(2)](#eq:b)"
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nskip_comments = false",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\tag{1} $$\n<!-- $$ draft \\htmlId{eq:d}{} \\tag{2} $$ -->\n$$ b \\htmlId{eq:b}{} \\tag{3} $$ [(3)](#eq:b)"
+        );
+        assert_eq!(find_comments("a <!-- b --> c <!-- d"), vec![2..12, 15..21]);
+    }
+
+    #[test]
+    fn same_equation_ref() {
+        // the link to an equation of the same chapter is a bare fragment, even from a sub-directory
+        let contents = run_book(
+            "[preprocessor.numeq]\nwarn_self_ref = true",
+            vec![chapter(
+                "A",
+                "$$ a {{numeq}}{eq:a} $$ {{eqref: eq:a}}",
+                "sub/a.md",
+                &[1],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\htmlId{eq:a}{} \\tag{1} $$ [(1)](#eq:a)"
+        );
+        assert_eq!(
+            compute_rel_path(Path::new("sub/a.md"), Path::new("sub/a.md")),
+            ""
+        );
+    }
+
+    #[test]
+    fn first_component_style() {
+        let chapters = || {
+            vec![
+                chapter("A", "$$ {{numeq}} $$", "a.md", &[2, 3]),
+                chapter("B", "$$ {{numeq}} $$", "b.md", &[14, 1]),
+            ]
+        };
+        let contents = run_book("[preprocessor.numeq]\nprefix = true", chapters());
+        assert_eq!(contents, vec!["$$ \\tag{2.3.1} $$", "$$ \\tag{14.1.1} $$"]);
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nfirst_component_style = \"roman\"",
+            chapters(),
+        );
+        assert_eq!(
+            contents,
+            vec!["$$ \\tag{II.3.1} $$", "$$ \\tag{XIV.1.1} $$"]
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\ndepth = 1\nfirst_component_style = \"alpha\"",
+            chapters(),
+        );
+        assert_eq!(contents, vec!["$$ \\tag{B.1} $$", "$$ \\tag{N.1} $$"]);
+        assert_eq!(format_component(1994, ComponentStyle::Roman), "MCMXCIV");
+        assert_eq!(format_component(0, ComponentStyle::Roman), "0");
+    }
+
+    #[test]
+    fn auto_anchor() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nauto_anchor = true",
+            vec![
+                chapter(
+                    "A",
+                    "$$ a {{numeq}} $$ $$ b {{numeq}}{eq:b} $$ {{eqref: 1.2.1}} {{eqref: 1}}",
+                    "a.md",
+                    &[1, 2],
+                ),
+                chapter(
+                    "B",
+                    "{{eqref: 1.2.1}} {{eqref: 1.2.2}} {{eqref: 1.2.3}}",
+                    "b.md",
+                    &[2],
+                ),
+            ],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\htmlId{eq-1-2-1}{} \\tag{1.2.1} $$ $$ b \\htmlId{eq:b}{} \\tag{1.2.2} $$ [(1.2.1)](#eq-1-2-1) [(1.2.1)](#eq-1-2-1)"
+        );
+        assert_eq!(
+            contents[1],
+            "[(1.2.1)](a.md#eq-1-2-1) [(1.2.2)](a.md#eq:b) **[??]**"
+        );
+    }
+
+    #[test]
+    fn continue_from_import() {
+        let info = |num: &str| LabelInfo {
+            num: num.to_string(),
+            path: PathBuf::from("vol1/a.md"),
+            ..Default::default()
+        };
+        let external = HashMap::from([
+            ("v1:a".to_string(), info("1.4")),
+            ("v1:b".to_string(), info("1.7b")),
+            ("v1:c".to_string(), info("2.3")),
+        ]);
+        let ctx =
+            context("[preprocessor.numeq]\nprefix = true\ndepth = 1\ncontinue_from_import = true");
+        let mut book = Book::new();
+        book.push_item(chapter("A", "$$ {{numeq}} $$", "a.md", &[1]));
+        book.push_item(chapter("B", "$$ {{numeq}} $$", "b.md", &[2]));
+        book.push_item(chapter("C", "$$ {{numeq}} $$", "c.md", &[3]));
+        let book = NumEqPreprocessor::new(&ctx)
+            .with_external_refs(external)
+            .run(&ctx, book)
+            .unwrap();
+        let contents: Vec<String> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch.content.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec!["$$ \\tag{1.8} $$", "$$ \\tag{2.4} $$", "$$ \\tag{3.1} $$"]
+        );
+        assert_eq!(split_number("12"), Some(("", 12)));
+        assert_eq!(split_number("A.3c"), Some(("A.", 3)));
+        assert_eq!(split_number("x"), None);
+        assert_eq!(split_number("1.2(3)b"), Some(("1.2(", 3)));
+    }
+
+    #[test]
+    fn ref_format_range() {
+        let content =
+            "$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$ $$ {{numeq}}{eq:c} $$ $$ {{numeq}} $$\n\n\
+            {{eqref: eq:a..eq:c}} {{eqref: 2..4}} {{eqref: eq:c..eq:a}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_format_range = \"{range}, {count} total\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert!(contents[0].ends_with(
+            "[(1)](#eq:a)\u{2013}[(3)](#eq:c), 3 total (2)\u{2013}(4), 3 total [(3)](#eq:c)\u{2013}[(1)](#eq:a)"
+        ));
+    }
+
+    #[test]
+    fn streams() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nstreams = [\"main\", \"aux\"]",
+            vec![
+                chapter(
+                    "A",
+                    "$$ {{numeq:main}}{eq:a} $$ $$ {{numeq:aux}}{eq:x} $$ $$ {{numeq:main}} $$ $$ {{numeq:aux}} $$ $$ {{numeq}} $$",
+                    "a.md",
+                    &[1],
+                ),
+                chapter("B", "$$ {{numeq:aux}} $$ {{eqref: eq:a, eq:x}}", "b.md", &[2]),
+            ],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:a}{} \\tag{1.1} $$ $$ \\htmlId{eq:x}{} \\tag{1.1} $$ $$ \\tag{1.2} $$ $$ \\tag{1.2} $$ $$ \\tag{1.1} $$"
+        );
+        // the counters of streams are reset together with the main counter
+        assert_eq!(
+            contents[1],
+            "$$ \\tag{2.1} $$ [(1.1)](a.md#eq:a) and [(1.1)](a.md#eq:x)"
+        );
+    }
+
+    #[test]
+    fn colon_labels() {
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter(
+                "A",
+                "$$ a {{numeq:eq:foo}} $$ $$ b {{numeq:start=5}} $$ $$ c {{numeq:start=8}}{eq:c} $$ $$ d {{numeq}} $$ {{eqref: eq:foo, eq:c}}",
+                "a.md",
+                &[1],
+            )],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\htmlId{eq:foo}{} \\tag{1} $$ $$ b \\tag{5} $$ $$ c \\htmlId{eq:c}{} \\tag{8} $$ $$ d \\tag{9} $$ [(1)](#eq:foo) and [(8)](#eq:c)"
+        );
+    }
+
+    #[test]
+    fn non_html_renderer() {
+        let run = |config: &str| {
+            let input = serde_json::json!([
+                {
+                    "root": "/path/to/book",
+                    "config": toml::from_str::<toml::Value>(config).unwrap(),
+                    "renderer": "markdown",
+                    "mdbook_version": mdbook::MDBOOK_VERSION,
+                },
+                Book::new(),
+            ]);
+            let (ctx, _) = CmdPreprocessor::parse_input(input.to_string().as_bytes()).unwrap();
+            let mut book = Book::new();
+            book.push_item(chapter(
+                "A",
+                "$$ a {{numeq}}{eq:a}{Caption} $$ $$ b {{numeq:eq:b}} $$ {{eqref: eq:a}} and {{cref: eq:a, eq:b|the last one}}",
+                "a.md",
+                &[1],
+            ));
+            let book = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap();
+            let BookItem::Chapter(ch) = &book.sections[0] else {
+                unreachable!()
+            };
+            ch.content.clone()
+        };
+        // equations are numbered for all renderers by default
+        let content = run("[preprocessor.numeq]");
+        assert!(content.starts_with("$$ a \\htmlId{eq:a}{} \\tag{1} $$"));
+        let content = run("[preprocessor.numeq]\nstrip_renderers = [\"markdown\"]");
+        assert!(!content.contains("{{") && !content.contains("\\tag"));
+        assert_eq!(
+            content,
+            "$$ a  $$ $$ b  $$ eq:a and Equations eq:a and the last one"
+        );
+        let content = run("[preprocessor.numeq]\nstrip_renderers = [\"epub\"]");
+        assert!(content.contains("\\tag{2}"));
+    }
+
+    #[test]
+    fn fallback_prefix() {
+        let chapters = || {
+            vec![
+                chapter("Preface", "$$ {{numeq}} $$", "preface.md", &[]),
+                chapter("A", "$$ {{numeq}} $$", "a.md", &[1]),
+            ]
+        };
+        let contents = run_book("[preprocessor.numeq]\nprefix = true", chapters());
+        assert_eq!(contents, vec!["$$ \\tag{1} $$", "$$ \\tag{1.1} $$"]);
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nfallback_prefix = \"0.\"",
+            chapters(),
+        );
+        assert_eq!(contents, vec!["$$ \\tag{0.1} $$", "$$ \\tag{1.1} $$"]);
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\ndepth = 1\nfallback_prefix = \"P.\"",
+            chapters(),
+        );
+        assert_eq!(contents, vec!["$$ \\tag{P.1} $$", "$$ \\tag{1.1} $$"]);
+    }
+
+    #[test]
+    fn process_chapter() {
+        let pre = NumEqPreprocessor {
+            require_labels: true,
+            ..Default::default()
+        };
+        let (content, diagnostics) = pre.process_chapter(
+            "$$ {{numeq}}{eq:a} $$ $$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$ {{eqref: eq:a}} {{eqref: eq:other}}",
+            "2.",
+            Path::new("a.md"),
+        );
+        assert_eq!(
+            content,
+            "$$ \\htmlId{eq:a}{} \\tag{2.1} $$ $$ \\htmlId{eq:a}{} \\tag{2.2} $$ $$ \\tag{2.3} $$ [(2.1)](#eq:a) **[??]**"
+        );
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| (d.kind, d.label.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                (DiagnosticKind::DuplicateLabel, Some("eq:a")),
+                (DiagnosticKind::MissingLabel, None),
+                (DiagnosticKind::UnresolvedReference, Some("eq:other")),
+            ]
+        );
+        let (content, diagnostics) =
+            NumEqPreprocessor::default().process_chapter("No equation.", "", Path::new("a.md"));
+        assert_eq!(content, "No equation.");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn reset_on_heading() {
+        let content = "$$ {{numeq}} $$\n\n## First\n\n$$ {{numeq}}{eq:a} $$ $$ {{numeq}} $$\n\n### Sub\n\n$$ {{numeq}} $$\n\n\
+            ## Second\n\n$$ {{numeq}} $$ {{eqref: eq:a}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nreset_on_heading = 2",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\tag{1} $$\n\n## First\n\n$$ \\htmlId{eq:a}{} \\tag{1} $$ $$ \\tag{2} $$\n\n### Sub\n\n$$ \\tag{3} $$\n\n\
+            ## Second\n\n$$ \\tag{1} $$ [(1)](#eq:a)"
+        );
+        assert_eq!(
+            find_headings("## a\n$$\n## b\n$$\n##c\n## d", 2),
+            vec![0, 20]
+        );
+    }
+
+    #[test]
+    fn number_refs() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nauto_anchor = true",
+            vec![
+                chapter(
+                    "A",
+                    "$$ a {{numeq}} $$ $$ b {{numeq}}{eq:b} $$ $$ c {{numeq}}{1.2.1} $$",
+                    "a.md",
+                    &[1, 2],
+                ),
+                chapter(
+                    "B",
+                    "{{eqref: (1.2.2)}} {{eqref: 1.2.1}} {{eqref: (1.2.1)}} {{eqref: 1.2.4}}",
+                    "b.md",
+                    &[2],
+                ),
+            ],
+        );
+        // the label `1.2.1' takes precedence over the number of the first equation, unlike `(1.2.1)'
+        // which is not a label
+        assert_eq!(
+            contents[1],
+            "[(1.2.2)](a.md#eq:b) [(1.2.3)](a.md#1.2.1) [(1.2.1)](a.md#eq-1-2-1) **[??]**"
+        );
+    }
+
+    #[test]
+    fn rtl() {
+        let content = "$$ a {{numeq}}{eq:a} $$ $$ b {{numeq}}{eq:b} $$ {{eqref: eq:a, eq:a..eq:b}} {{eqref: eq:c, line=2}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nrtl = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ a \\htmlId{eq:a}{} \\tag{1} $$ $$ b \\htmlId{eq:b}{} \\tag{2} $$ \
+             <bdi>[(1)](#eq:a)</bdi> and <bdi>[(1)](#eq:a)</bdi>\u{2013}<bdi>[(2)](#eq:b)</bdi> <bdi>**[??]**, line 2</bdi>"
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]\nrtl = true\nnumber_position = \"above\"",
+            vec![chapter("A", "$$\na {{numeq}}\n$$", "a.md", &[1])],
+        );
+        assert_eq!(contents[0], "<bdi>**(1)**</bdi>\n\n$$\na \n$$");
+    }
+
+    #[test]
+    fn collapse_repeats() {
+        let content = "$$ {{numeq}}{eq:a} $$ $$ {{numeq}}{eq:b} $$\n\n\
+            By {{eqref: eq:a}} and {{cref: eq:a}}, then {{eqref: eq:b}} and {{eqref: eq:a}}.\n\n\
+            Much later in the chapter, {{eqref: eq:a}}.";
+        let contents = run_book(
+            "[preprocessor.numeq]\ncollapse_repeats = 20",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert!(contents[0].ends_with(
+            "By [(1)](#eq:a) and Equation (1), then [(2)](#eq:b) and [(1)](#eq:a).\n\n\
+            Much later in the chapter, [(1)](#eq:a)."
+        ));
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert!(contents[0].contains("By [(1)](#eq:a) and Equation [(1)](#eq:a), then"));
+    }
+
+    #[test]
+    fn html_div_and_code_blocks() {
+        let content = "<div class=\"math\">\n<!-- draft: $$ {{numeq}} $$ -->\n$$ a {{numeq}}{eq:a} $$\n</div>\n\n\
+            ```html\n<div class=\"math\">\n```\n\n\
+            <div class=\"math\">$$ b {{numeq}}{eq:b} $$</div>\n\n`<div>` {{eqref: eq:a}} {{eqref: eq:b}}";
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "<div class=\"math\">\n<!-- draft: $$ {{numeq}} $$ -->\n$$ a \\htmlId{eq:a}{} \\tag{1} $$\n</div>\n\n\
+            ```html\n<div class=\"math\">\n```\n\n\
+            <div class=\"math\">$$ b \\htmlId{eq:b}{} \\tag{2} $$</div>\n\n`<div>` [(1)](#eq:a) [(2)](#eq:b)"
+        );
+    }
+
+    #[test]
+    fn title_placeholder() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_format_remote = \"({n} in {title})\"",
+            vec![
+                chapter("The *Group* `Law`", "$$ {{numeq}}{eq:a} $$", "a.md", &[1]),
+                chapter("B", "{{eqref: eq:a}}", "b.md", &[2]),
+            ],
+        );
+        assert_eq!(contents[1], "[(1 in The Group Law)](a.md#eq:a)");
+        assert_eq!(
+            strip_markdown("See [groups](groups.md) and **rings**"),
+            "See groups and rings"
+        );
+    }
+
+    #[test]
+    fn chapter_prefixes() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\n\n[preprocessor.numeq.chapter_prefixes]\n\"appendix.md\" = \"A\"",
+            vec![
+                chapter("One", "$$ {{numeq}} $$ $$ {{numeq}}{eq:a} $$", "one.md", &[1]),
+                chapter("Appendix A", "$$ {{numeq}} $$ {{eqref: eq:a}}", "appendix.md", &[2]),
+                chapter("Two", "$$ {{numeq}} $$", "two.md", &[3]),
+            ],
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\tag{1.1} $$ $$ \\htmlId{eq:a}{} \\tag{1.2} $$",
+                "$$ \\tag{A.1} $$ [(1.2)](one.md#eq:a)",
+                "$$ \\tag{3.1} $$"
+            ]
+        );
+    }
+
+    #[test]
+    fn chapter_name_in_warnings() {
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]",
+                vec![chapter(
+                    "Groups",
+                    "<!-- numeq: oops -->$$ {{numeq}}{eq:a} $$ {{eqref: eq:x}}",
+                    "crypto/groups.md",
+                    &[1],
+                )],
+            );
+            assert_eq!(contents, vec!["$$ \\htmlId{eq:a}{} \\tag{1} $$ **[??]**"]);
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "In chapter \"Groups\" (crypto/groups.md): Unknown directive `oops'",
+                "In chapter \"Groups\" (crypto/groups.md): Unknown equation reference: eq:x"
+            ]
+        );
+    }
+
+    #[test]
+    fn collapse_ranges() {
+        let content = "$$ {{numeq}}{a} $$ $$ {{numeq}}{b} $$ $$ {{numeq}}{c} $$ $$ {{numeq}}{d} $$ $$ {{numeq}}{e} $$\n\n\
+            {{eqref: a, b, c, d}}\n\n{{eqref: a, b, c, e}}\n\n{{cref: a, c, e}}\n\n{{eqref: a, b}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\ncollapse_ranges = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        let refs: Vec<&str> = contents[0].split("\n\n").skip(1).collect();
+        assert_eq!(
+            refs,
+            vec![
+                "[(1)](#a)–[(4)](#d)",
+                "[(1)](#a)–[(3)](#c) and [(5)](#e)",
+                "Equations [(1)](#a), [(3)](#c) and [(5)](#e)",
+                "[(1)](#a) and [(2)](#b)",
+            ]
+        );
+    }
+
+    #[test]
+    fn require_math() {
+        let ctx = context("[preprocessor.numeq]\nrequire_math = true");
+        let pre = NumEqPreprocessor::new(&ctx);
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "$$ a {{numeq}}{eq:a} $$\n<!-- {{numeq}} -->",
+            "a.md",
+            &[1],
+        ));
+        assert!(pre.run(&ctx, book).is_ok());
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "$$ a {{numeq}}{eq:a} $$\n\nb {{numeq}}{eq:b}",
+            "a.md",
+            &[1],
+        ));
+        let err = pre.run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "In chapter \"A\" (a.md): Marker `{{numeq}}{eq:b}' outside of a math block (line 3, column 3)"
+        );
+    }
+
+    #[test]
+    fn unbalanced_braces_in_labels() {
+        let content = "$$ {{numeq}}{eq:\\{} $$\n\n{{eqref: eq:\\{}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\ncolon_replacement = \"-\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq--}{} \\tag{1} $$\n\n[(1)](#eq--)"
+        );
+    }
+
+    #[test]
+    fn counter_separator() {
+        let mut ch = chapter("A", "$$ {{numeq}}{eq:a} $$", "a.md", &[1]);
+        ch.sub_items.push(BookItem::Chapter(chapter(
+            "B",
+            "$$ {{numeq}} $$ $$ {{numeq}} $$ $$ {{numeq}} $$ {{eqref: eq:a}}",
+            "b.md",
+            &[1, 2],
+        )));
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\ncounter_separator = \"-\"",
+            vec![ch],
+        );
+        assert_eq!(contents[0], "$$ \\htmlId{eq:a}{} \\tag{1-1} $$");
+        assert_eq!(
+            contents[1],
+            "$$ \\tag{1.2-1} $$ $$ \\tag{1.2-2} $$ $$ \\tag{1.2-3} $$ [(1-1)](a.md#eq:a)"
+        );
+    }
+
+    #[test]
+    fn prefix_separators() {
+        let chapters = || {
+            let mut ch = chapter("A", "$$ {{numeq}}{eq:a} $$", "a.md", &[1]);
+            let mut sub = chapter("B", "", "b.md", &[1, 2]);
+            sub.sub_items.push(BookItem::Chapter(chapter(
+                "C",
+                "$$ {{numeq}} $$ {{eqref: eq:a}}",
+                "c.md",
+                &[1, 2, 3],
+            )));
+            ch.sub_items.push(BookItem::Chapter(sub));
+            vec![ch]
+        };
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_separators = [\"–\", \".\"]",
+            chapters(),
+        );
+        assert_eq!(contents[0], "$$ \\htmlId{eq:a}{} \\tag{1.1} $$");
+        assert_eq!(contents[2], "$$ \\tag{1–2.3.1} $$ [(1.1)](a.md#eq:a)");
+
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nprefix_separators = [\"–\"]\ncounter_separator = \"-\"",
+            chapters(),
+        );
+        assert_eq!(contents[2], "$$ \\tag{1–2–3-1} $$ [(1-1)](a.md#eq:a)");
+    }
+
+    #[test]
+    fn stable_ids() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nstable_ids = true",
+            vec![
+                chapter("A", "$$ {{numeq}} $$ $$ {{numeq}}{eq:a} $$", "a.md", &[1]),
+                chapter(
+                    "B",
+                    "$$ {{numeq}}{eq:b} $$ {{eqref: eq:a}} {{eqref: eq:b}}",
+                    "b.md",
+                    &[2],
+                ),
+            ],
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\tag{1.1} $$ $$ \\htmlId{eq-2}{} \\tag{1.2} $$",
+                "$$ \\htmlId{eq-3}{} \\tag{2.1} $$ [(1.2)](a.md#eq-2) [(2.1)](#eq-3)"
+            ]
+        );
+    }
+
+    #[test]
+    fn blockquotes() {
+        let content = "$$ {{numeq}}{eq:a} $$\n\n\
+            > [!NOTE]\n> By {{eqref: eq:a}} and {{eqref: eq:b}},\n> $$ b {{numeq}}{eq:b} $$\n\n\
+            > **Note**\n> > Nested {{cref: eq:a, eq:b}}.";
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:a}{} \\tag{1} $$\n\n\
+            > [!NOTE]\n> By [(1)](#eq:a) and [(2)](#eq:b),\n> $$ b \\htmlId{eq:b}{} \\tag{2} $$\n\n\
+            > **Note**\n> > Nested Equations [(1)](#eq:a) and [(2)](#eq:b)."
+        );
+
+        // the content of a blockquote transformed by an admonition preprocessor running before numeq
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]",
+                vec![chapter(
+                    "A",
+                    "<div class=\"admonition\"><p>By &#123;&#123;eqref: eq:a&#125;&#125;</p></div>",
+                    "a.md",
+                    &[1],
+                )],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Found escaped equation markers or references, which are not processed (another preprocessor may have to run after numeq)"]
+        );
+    }
+
+    #[test]
+    fn env_overrides() {
+        let ctx = context(
+            "[preprocessor.numeq]\nprefix = true\nfirst_component_style = \"alpha\"\ndepth = 2",
+        );
+        let env = |var: &str| match var {
+            "NUMEQ_STYLE" => Some("roman".to_string()),
+            "NUMEQ_DEPTH" => Some("1".to_string()),
+            "NUMEQ_REF_FORMAT" => Some("[{n}]".to_string()),
+            _ => None,
+        };
+        let pre = NumEqPreprocessor::new_with_env(&ctx, env);
+        assert_eq!(pre.first_component_style, ComponentStyle::Roman);
+        assert_eq!(pre.prefix_depth, 1);
+        assert_eq!(pre.ref_format, "[{n}]");
+        // options without environment variable keep their configured value
+        assert!(pre.with_prefix);
+
+        let pre = NumEqPreprocessor::new_with_env(&ctx, |_| None);
+        assert_eq!(pre.first_component_style, ComponentStyle::Alpha);
+        assert_eq!(pre.prefix_depth, 2);
+    }
+
+    #[test]
+    fn dedup_refs() {
+        let content = "$$ {{numeq}}{a} $$ $$ {{numeq}}{b} $$\n\n\
+            {{eqref: a, a, b}}\n\n{{cref: a, b, line=2, a, b, line=3}}\n\n{{cref: a, a}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\ndedup_refs = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        let refs: Vec<&str> = contents[0].split("\n\n").skip(1).collect();
+        assert_eq!(
+            refs,
+            vec![
+                "[(1)](#a) and [(2)](#b)",
+                "Equations [(1)](#a) and [(2), line 2](#b)",
+                "Equation [(1)](#a)",
+            ]
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        let refs: Vec<&str> = contents[0].split("\n\n").skip(1).collect();
+        assert_eq!(refs[0], "[(1)](#a), [(1)](#a) and [(2)](#b)");
+        assert_eq!(refs[2], "Equations [(1)](#a) and [(1)](#a)");
+    }
+
+    #[test]
+    fn label_pattern() {
+        let chapters = || {
+            vec![chapter(
+                "A",
+                "$$ {{numeq}}{eq:good_1} $$ $$ {{numeq}}{Bad} $$ $$ {{numeq}} $$",
+                "a.md",
+                &[1],
+            )]
+        };
+        let config = "[preprocessor.numeq]\nlabel_pattern = \"^eq:[a-z0-9_-]+$\"";
+        let warnings = capture_warnings(|| {
+            let contents = run_book(config, chapters());
+            assert!(contents[0].contains("\\htmlId{Bad}{} \\tag{2}"));
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Eq. 2: Label `Bad' does not match pattern `^eq:[a-z0-9_-]+$'"]
+        );
+
+        let ctx = context(&format!("{config}\nstrict = true"));
+        let mut book = Book::new();
+        for ch in chapters() {
+            book.push_item(ch);
+        }
+        let err = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "In chapter \"A\" (a.md): Eq. 2: Label `Bad' does not match pattern `^eq:[a-z0-9_-]+$'"
+        );
+    }
+
+    #[test]
+    fn one_per_block() {
+        let content = "$$\n\\begin{aligned}\na &= b {{numeq}}{eq:a} \\\\\nc &= d {{numeq}}{eq:c}\n\\end{aligned}\n$$\n\n\
+            $$ e {{numeq}} $$ {{eqref: eq:a}}";
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]\none_per_block = true",
+                vec![chapter("A", content, "a.md", &[1])],
+            );
+            assert_eq!(
+                contents[0],
+                "$$\n\\begin{aligned}\na &= b \\htmlId{eq:a}{} \\tag{1} \\\\\nc &= d \n\\end{aligned}\n$$\n\n\
+                $$ e \\tag{2} $$ [(1)](#eq:a)"
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["In chapter \"A\" (a.md): Only the first marker of a math block is numbered with option one_per_block, ignoring marker `{{numeq}}{eq:c}'"]
+        );
+    }
+
+    #[test]
+    fn debug_labels() {
+        let content = "$$ {{numeq}}{eq:f_1} $$ $$ {{numeq}} $$ {{eqref: eq:f_1}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\ndebug_labels = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:f_1}{} \\tag{1 eq:f\\_1} $$ $$ \\tag{2} $$ [(1)](#eq:f_1)"
+        );
+        let contents = run_book(
+            "[preprocessor.numeq]",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:f_1}{} \\tag{1} $$ $$ \\tag{2} $$ [(1)](#eq:f_1)"
+        );
+    }
+
+    #[test]
+    fn github_annotations() {
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]\nci = \"github\"",
+                vec![chapter(
+                    "Groups",
+                    "$$ {{numeq}}{eq:a} $$ {{eqref: eq:x}}\n\n  $$ {{numeq}}{eq:a} $$\n\n{{cref: eq:a|x}}",
+                    "crypto/groups.md",
+                    &[1],
+                )],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "::warning file=src/crypto/groups.md,line=3,col=6::In chapter \"Groups\" (crypto/groups.md): Eq. 2: Label `eq:a' already used for Eq. 1 in crypto/groups.md",
+                "::warning file=src/crypto/groups.md::In chapter \"Groups\" (crypto/groups.md): Unknown equation reference: eq:x"
+            ]
+        );
+        // errors of strict mode are annotated as errors, with the positions of markers in regions
+        let ctx = context(
+            "[preprocessor.numeq]\nci = \"github\"\nstrict = true\nrequire_labels = true\nregions = true",
+        );
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "Text\n{{numeq-begin}}$$ {{numeq}} $$\n$$ {{numeq}} $${{numeq-end}}",
+            "a.md",
+            &[1],
+        ));
+        let err = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\n::error file=src/a.md,line=2,col=19::In chapter \"A\" (a.md): Eq. 1: Missing label\
+             \n::error file=src/a.md,line=3,col=4::In chapter \"A\" (a.md): Eq. 2: Missing label"
+        );
+    }
+
+    #[test]
+    fn show_chapter_on_crosspage() {
+        let chapters = || {
+            vec![
+                chapter(
+                    "Groups",
+                    "$$ {{numeq}}{eq:a} $$ {{eqref: eq:a}}",
+                    "groups.md",
+                    &[1],
+                ),
+                chapter("Rings", "{{eqref: eq:a}} {{cref: eq:a}}", "rings.md", &[2]),
+            ]
+        };
+        let contents = run_book(
+            "[preprocessor.numeq]\nshow_chapter_on_crosspage = true",
+            chapters(),
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\htmlId{eq:a}{} \\tag{1} $$ [(1)](#eq:a)",
+                "[(1) in Groups](groups.md#eq:a) Equation [(1) in Groups](groups.md#eq:a)"
+            ]
+        );
+        let contents = run_book("[preprocessor.numeq]", chapters());
+        assert_eq!(
+            contents[1],
+            "[(1)](groups.md#eq:a) Equation [(1)](groups.md#eq:a)"
+        );
+    }
+
+    #[test]
+    fn forward_references() {
+        let ctx = context("[preprocessor.numeq]\nprefix = true");
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "By {{eqref: eq:c}}, {{eqref: eq:b}} and {{cref: eq:b..eq:c}}.",
+            "a.md",
+            &[1],
+        ));
+        let mut b = chapter("B", "", "b.md", &[2]);
+        b.sub_items.push(BookItem::Chapter(chapter(
+            "B.1",
+            "$$ {{numeq}}{eq:b} $$ {{eqref: eq:c}}",
+            "b/one.md",
+            &[2, 1],
+        )));
+        book.push_item(b);
+        book.push_item(chapter("C", "$$ {{numeq}}{eq:c} $$", "c.md", &[3]));
+        let (book, summary) = NumEqPreprocessor::new(&ctx)
+            .run_with_summary(&ctx, book)
+            .unwrap();
+        // references to equations of later chapters are resolved in a single run
+        assert!(summary.unresolved.is_empty());
+        let contents: Vec<String> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(ch) => Some(ch.content.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "By [(3.1)](c.md#eq:c), [(2.1.1)](b/one.md#eq:b) and Equations [(2.1.1)](b/one.md#eq:b)\u{2013}[(3.1)](c.md#eq:c).",
+                "",
+                "$$ \\htmlId{eq:b}{} \\tag{2.1.1} $$ [(3.1)](../c.md#eq:c)",
+                "$$ \\htmlId{eq:c}{} \\tag{3.1} $$"
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_braces() {
+        let content = "$$ {{numeq}}{eq:{sub}} $$ $$ {{numeq}}{eq:f_{a{b}}}{The {caption}} $$ $$ {{numeq}}{eq:\\{} $$\n\
+            {{eqref: eq:{sub}}} {{eqref: eq:f_{a{b}}, eq:\\{}} {{cref: eq:{sub}..eq:f_{a{b}}}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nnested_braces = true\nref_with_caption = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:-sub-}{} \\tag{1} $$ $$ \\htmlId{eq:f_-a-b--}{} \\tag{2} $$ $$ \\htmlId{eq:-}{} \\tag{3} $$\n\
+            [(1)](#eq:-sub-) [The {caption} (2)](#eq:f_-a-b--) and [(3)](#eq:-) \
+            Equations [(1)](#eq:-sub-)\u{2013}[The {caption} (2)](#eq:f_-a-b--)"
+        );
+        assert_eq!(
+            escape_nested_braces("{{numeq}}{a{b}}[k=v] {{numeq}}{a\n} {{cref: {x}}}", |_| {
+                true
+            }),
+            "{{numeq}}{a\\{b\\}}[k=v] {{numeq}}{a\n} {{cref: \\{x\\}}}"
+        );
+    }
+
+    #[test]
+    fn bracket_math_blocks() {
+        let content = "$$ a $$ \\[ b \\] text \\\\[ c \\\\] $$ d \\] $$ \\[ e";
+        assert_eq!(find_math_blocks(content), vec![0..7, 8..15, 21..30, 31..41]);
+
+        let content = "\\[ a {{numeq}}{eq:a} \\]\n\n$$ b {{numeq}} $$\n\n\\\\[ c {{numeq}}{eq:c} \\\\]\n\n{{eqref: eq:c}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nnumber_position = \"above\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "<span id=\"eq:a\"></span>**(1)**\n\n\\[ a  \\]\n\n**(2)**\n\n$$ b  $$\n\n\
+            <span id=\"eq:c\"></span>**(3)**\n\n\\\\[ c  \\\\]\n\n[(3)](#eq:c)"
+        );
+    }
+
+    #[test]
+    fn ref_class() {
+        let contents = run_book(
+            "[preprocessor.numeq]\nref_class = \"eq-ref\"",
+            vec![
+                chapter(
+                    "A",
+                    "$$ {{numeq}}{eq:a} $$ {{eqref: eq:a, line=2}}",
+                    "a.md",
+                    &[1],
+                ),
+                chapter("B", "{{cref: eq:a}} {{eqref: eq:a|this}}", "b/b.md", &[2]),
+            ],
+        );
+        assert_eq!(
+            contents,
+            vec![
+                "$$ \\htmlId{eq:a}{} \\tag{1} $$ <a class=\"eq-ref\" href=\"#eq:a\">(1), line 2</a>",
+                "Equation <a class=\"eq-ref\" href=\"../a.md#eq:a\">(1)</a> <a class=\"eq-ref\" href=\"../a.md#eq:a\">this</a>"
+            ]
+        );
+    }
+
+    #[test]
+    fn number_transform() {
+        assert_eq!(transform_number("1.2a", NumberTransform::None), "1.2a");
+        assert_eq!(transform_number("1.2a", NumberTransform::Upper), "1.2A");
+        assert_eq!(
+            transform_number("12345.1234.1234567b", NumberTransform::ThinSpaceGroups),
+            "12\u{2009}345.1234.1\u{2009}234\u{2009}567b"
+        );
+
+        let content = "$$ {{numeq:family=f}}{eq:a} $$ {{eqref: eq:a}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nprefix = true\nnumber_transform = \"upper\"",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ \\htmlId{eq:a}{} \\tag{1.1A} $$ [(1.1A)](#eq:a)"
+        );
+
+        let ctx = context("[preprocessor.numeq]\nnumber_transform = \"thin_space_groups\"");
+        let pre = NumEqPreprocessor::new(&ctx);
+        let mut refs = HashMap::new();
+        let mut ctr = Counters {
+            main: 99999,
+            ..Default::default()
+        };
+        let output = pre.find_and_replace_eqs(
+            "$$ {{numeq}}{eq:a} $$",
+            "",
+            "Groups",
+            &PATH,
+            &mut refs,
+            &mut ctr,
+            &mut Vec::new(),
+        );
+        assert_eq!(output, "$$ \\htmlId{eq:a}{} \\tag{100\u{2009}000} $$");
+        assert_eq!(refs["eq:a"].num, "100\u{2009}000");
+    }
+
+    #[test]
+    fn external_table() {
+        let config = "[preprocessor.numeq]\n\n[preprocessor.numeq.external]\n\
+            \"ext:foo\" = { num = \"4.2\", url = \"https://example.com/paper.pdf#page=3\" }\n\
+            \"ext:bar\" = { num = 7 }\n\"ext:baz\" = { url = \"https://example.com\" }";
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                config,
+                vec![chapter(
+                    "A",
+                    "$$ {{numeq}}{ext:foo} $$ {{eqref: ext:foo}} {{cref: ext:foo, ext:bar}}",
+                    "a.md",
+                    &[1],
+                )],
+            );
+            assert_eq!(
+                contents[0],
+                "$$ \\htmlId{ext:foo}{} \\tag{1} $$ [(4.2)](https://example.com/paper.pdf#page=3) \
+                Equations [(4.2)](https://example.com/paper.pdf#page=3) and **[??]**"
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "Ignoring external equation `ext:bar' without URL in option external",
+                "Ignoring external equation `ext:baz' without number in option external",
+                "In chapter \"A\" (a.md): Eq. 1: Label `ext:foo' already used for external Eq. 4.2 at https://example.com/paper.pdf#page=3",
+                "In chapter \"A\" (a.md): Unknown equation reference: ext:bar"
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_integer_options() {
+        let warnings = capture_warnings(|| {
+            let pre = NumEqPreprocessor::new(&context(
+                "[preprocessor.numeq]\ndepth = -1\nanchor_offset = 3\ncollapse_repeats = -2",
+            ));
+            let default = NumEqPreprocessor::new(&context("[preprocessor.numeq]"));
+            assert_eq!(pre.prefix_depth, default.prefix_depth);
+            assert_eq!(pre.anchor_offset, 3);
+            assert_eq!(pre.collapse_repeats, 0);
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "Ignoring invalid value `-1' for option depth, expected a non-negative integer",
+                "Ignoring invalid value `-2' for option collapse_repeats, expected a non-negative integer"
+            ]
+        );
+    }
+
+    #[test]
+    fn small_max_label_len() {
+        let warnings = capture_warnings(|| {
+            let contents = run_book(
+                "[preprocessor.numeq]\nmax_label_len = 4",
+                vec![chapter(
+                    "A",
+                    "$$ a {{numeq}}{eq:a-very-long-label} $$",
+                    "a.md",
+                    &[1],
+                )],
+            );
+            let truncated = NumEqPreprocessor {
+                max_label_len: Some(9),
+                ..Default::default()
+            }
+            .shorten_label("eq:a-very-long-label".to_string());
+            assert_eq!(truncated.chars().count(), 9);
+            assert_eq!(
+                contents[0],
+                format!("$$ a \\htmlId{{{truncated}}}{{}} \\tag{{1}} $$")
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["Value `4' of option max_label_len is too small, using 9 instead"]
+        );
+    }
+
+    #[test]
+    fn require_math_families_and_regions() {
+        let ctx = context("[preprocessor.numeq]\nrequire_math = true");
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "$$\na = b {{numeq:family=f1}}{eq:first}\n$$\n\n$$\nc = d {{numeq:family=f1}}\n$$\n\n{{numeq:endfamily}}",
+            "a.md",
+            &[1],
+        ));
+        assert!(NumEqPreprocessor::new(&ctx).run(&ctx, book).is_ok());
+
+        let ctx = context("[preprocessor.numeq]\nrequire_math = true\nregions = true");
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "`{{numeq}}` {{numeq-begin}}$$ a {{numeq}} $${{numeq-end}}",
+            "a.md",
+            &[1],
+        ));
+        assert!(NumEqPreprocessor::new(&ctx).run(&ctx, book).is_ok());
+        let mut book = Book::new();
+        book.push_item(chapter(
+            "A",
+            "{{numeq-begin}}$$ a $$ {{numeq}}{{numeq-end}}",
+            "a.md",
+            &[1],
+        ));
+        assert!(NumEqPreprocessor::new(&ctx).run(&ctx, book).is_err());
+    }
+
+    #[test]
+    fn grouped_sub_chapters_with_nested_chapter() {
+        let mut a = chapter("A", "<!-- numeq: group -->\n{{numeq}}", "a.md", &[1]);
+        let mut a1 = chapter("A1", "{{numeq}}", "a1.md", &[1, 1]);
+        a1.parent_names = vec!["A".to_string()];
+        let mut a11 = chapter("A11", "{{numeq}}", "a11.md", &[1, 1, 1]);
+        a11.parent_names = vec!["A".to_string(), "A1".to_string()];
+        a1.sub_items = vec![BookItem::Chapter(a11)];
+        let mut a2 = chapter("A2", "{{numeq}}", "a2.md", &[1, 2]);
+        a2.parent_names = vec!["A".to_string()];
+        a.sub_items = vec![BookItem::Chapter(a1), BookItem::Chapter(a2)];
+        let contents = run_book("[preprocessor.numeq]\nprefix = true", vec![a]);
+        // the nested chapter has its own numbering, which does not affect the group
+        assert_eq!(
+            contents,
+            vec!["\\tag{1.1}", "\\tag{1.2}", "\\tag{1.1.1.1}", "\\tag{1.3}"]
+        );
+    }
+
+    #[test]
+    fn preserve_layout() {
+        let process = |config: &str, input: &str| {
+            NumEqPreprocessor::new(&context(&format!("[preprocessor.numeq]\n{config}")))
+                .find_and_replace_eqs(
+                    input,
+                    "",
+                    "Groups",
+                    &PATH,
+                    &mut HashMap::new(),
+                    &mut Counters::default(),
+                    &mut Vec::new(),
+                )
+        };
+        for config in ["", "preserve_layout = true"] {
+            // markers on their own line, at the end of a line and inline, which are replaced
+            // by numbers are kept in place in both modes
+            assert_eq!(
+                process(config, "$$\na = b\n{{numeq}}\n$$\n\nNext paragraph."),
+                "$$\na = b\n\\tag{1}\n$$\n\nNext paragraph."
+            );
+            assert_eq!(
+                process(config, "$$\na = b {{numeq}}{eq:a}\n$$\n"),
+                "$$\na = b \\htmlId{eq:a}{} \\tag{1}\n$$\n"
+            );
+            assert_eq!(
+                process(config, "$$ a = b {{numeq}} $$ and text"),
+                "$$ a = b \\tag{1} $$ and text"
+            );
+        }
+        // removed markers (here closing a family) on their own line also remove the line by default
+        let content = "$$ a {{numeq:family=f}} $$\n{{numeq:endfamily}}\nText {{numeq:endfamily}}\n\n$$ b {{numeq}} $${{numeq:endfamily}}\n{{numeq:endfamily}}";
+        let family = "$$ a \\tag{1a} $$";
+        assert_eq!(
+            process("", content),
+            format!("{family}\nText \n\n$$ b \\tag{{2}} $$")
+        );
+        assert_eq!(
+            process("preserve_layout = true", content),
+            format!("{family}\n\nText \n\n$$ b \\tag{{2}} $$\n")
+        );
+    }
+
+    #[test]
+    fn colon_label_checks() {
+        // labels given with the colon syntax are checked like labels given in braces
+        let content =
+            "$$ a {{numeq:eq:a-very-long-label}} $$ $$ b {{numeq:start=1234567890123}} $$";
+        let ctx = context("[preprocessor.numeq]\nmax_label_len = 12\nstrict = true");
+        let mut book = Book::new();
+        book.push_item(chapter("A", content, "a.md", &[1]));
+        let err = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "In chapter \"A\" (a.md): Label `eq:a-very-long-label' is longer than 12 characters"
+        );
+        let content = "$$ a {{numeq:eq:a}} \\text{by {{eqref: eq:a}}} $$ $$ b {{numeq:thm}} $$ {{eqref: thm}}";
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]\nwarn_self_ref = true\nstreams = [\"thm\"]",
+                vec![chapter("A", content, "a.md", &[1])],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec![
+                "In chapter \"A\" (a.md): Equation `eq:a' is referenced within its own block",
+                "In chapter \"A\" (a.md): Unknown equation reference: thm"
+            ]
+        );
+    }
+
+    #[test]
+    fn a11y_all_outputs() {
+        let content = "$$ {{numeq}}{eq:a} $$";
+        for (config, expected) in [
+            (
+                "output = \"data-attr\"",
+                "<div class=\"numeq\" data-eqnum=\"1\" id=\"eq:a\">\n\n<span class=\"sr-only\">Equation 1</span>$$  $$\n\n</div>",
+            ),
+            (
+                "number_position = \"above\"",
+                "<span id=\"eq:a\"></span>**(1)**\n\n<span class=\"sr-only\">Equation 1</span>$$  $$",
+            ),
+            (
+                "wrap_block = true",
+                "<div class=\"equation\" id=\"eq:a\">\n\n<span class=\"sr-only\">Equation 1</span>$$ \\tag{1} $$\n\n</div>",
+            ),
+        ] {
+            let contents = run_book(
+                &format!("[preprocessor.numeq]\na11y = true\n{config}"),
+                vec![chapter("A", content, "a.md", &[1])],
+            );
+            assert_eq!(contents[0], expected, "{config}");
+        }
+    }
+
+    #[test]
+    fn searchable_all_outputs() {
+        let content = "$$ {{numeq}}{eq:a} $$";
+        for (config, expected) in [
+            (
+                "output = \"data-attr\"",
+                "<div class=\"numeq\" data-eqnum=\"1\" id=\"eq:a\">\n\n<span hidden>Equation 1</span>$$  $$\n\n</div>",
+            ),
+            (
+                "number_position = \"above\"",
+                "<span id=\"eq:a\"></span>**(1)**\n\n<span hidden>Equation 1</span>$$  $$",
+            ),
+            (
+                "wrap_block = true",
+                "<div class=\"equation\" id=\"eq:a\">\n\n<span hidden>Equation 1</span>$$ \\tag{1} $$\n\n</div>",
+            ),
+            (
+                "a11y = true",
+                "<span class=\"sr-only\">Equation 1</span><span hidden>Equation 1</span>$$ \\htmlId{eq:a}{} \\tag{1} $$",
+            ),
+        ] {
+            let contents = run_book(
+                &format!("[preprocessor.numeq]\nsearchable = true\n{config}"),
+                vec![chapter("A", content, "a.md", &[1])],
+            );
+            assert_eq!(contents[0], expected, "{config}");
+        }
+    }
+
+    #[test]
+    fn github_annotations_encoding() {
+        let warnings = capture_warnings(|| {
+            run_book(
+                "[preprocessor.numeq]\nci = \"github\"",
+                vec![chapter(
+                    "A",
+                    "$$ {{numeq}}{eq:a} $$ $$ {{numeq}}{eq:a} $$",
+                    "rings, fields.md",
+                    &[1],
+                )],
+            );
+        });
+        assert_eq!(
+            warnings,
+            vec!["::warning file=src/rings%2C fields.md,line=1,col=26::In chapter \"A\" (rings, fields.md): Eq. 2: Label `eq:a' already used for Eq. 1 in rings, fields.md"]
+        );
+        let ctx =
+            context("[preprocessor.numeq]\nci = \"github\"\nstrict = true\nrequire_labels = true");
+        let mut book = Book::new();
+        book.push_item(chapter("100%", "$$ {{numeq}} $$", "a.md", &[1]));
+        let err = NumEqPreprocessor::new(&ctx).run(&ctx, book).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\n::error file=src/a.md,line=1,col=4::In chapter \"100%25\" (a.md): Eq. 1: Missing label"
+        );
+    }
+
+    #[test]
+    fn nested_braces_unprocessed_markers() {
+        // markers which are not numbered are left as is, without escaped braces
+        let content =
+            "<!-- $$ {{numeq}}{eq:{old}} $$ -->\n$$ {{numeq}}{eq:{sub}} $$ {{eqref: eq:{sub}}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nnested_braces = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "<!-- $$ {{numeq}}{eq:{old}} $$ -->\n$$ \\htmlId{eq:-sub-}{} \\tag{1} $$ [(1)](#eq:-sub-)"
+        );
+        let content = "$$ {{numeq}}{eq:{a}} $$ {{numeq-begin}}$$ {{numeq}}{eq:{b}} $${{numeq-end}}";
+        let contents = run_book(
+            "[preprocessor.numeq]\nnested_braces = true\nregions = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(
+            contents[0],
+            "$$ {{numeq}}{eq:{a}} $$ $$ \\htmlId{eq:-b-}{} \\tag{1} $$"
+        );
+        let content = "$$ {{numeq}}{eq:{a}} $$ $$ b \\tag{B} $$";
+        let contents = run_book(
+            "[preprocessor.numeq]\nnested_braces = true\nskip_manual_tags = true",
+            vec![chapter("A", content, "a.md", &[1])],
+        );
+        assert_eq!(contents[0], content);
+    }
+
+    #[test]
+    fn require_math_untouched_markers() {
+        // markers which are left untouched or removed are not required to be in math blocks
+        for config in [
+            "bare = \"ignore\"",
+            "bare = \"remove\"",
+            "skip_manual_tags = true",
+        ] {
+            let ctx = context(&format!(
+                "[preprocessor.numeq]\nrequire_math = true\n{config}"
+            ));
+            let mut book = Book::new();
+            book.push_item(chapter(
+                "A",
+                "See `{{numeq}}`. $$ a \\tag{A} $$",
+                "a.md",
+                &[1],
+            ));
+            assert!(
+                NumEqPreprocessor::new(&ctx).run(&ctx, book).is_ok(),
+                "{config}"
+            );
+        }
+        let ctx = context("[preprocessor.numeq]\nrequire_math = true\nbare = \"ignore\"");
+        let mut book = Book::new();
+        book.push_item(chapter("A", "See {{numeq}}{eq:a}.", "a.md", &[1]));
+        assert!(NumEqPreprocessor::new(&ctx).run(&ctx, book).is_err());
     }
 }
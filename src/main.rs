@@ -1,9 +1,14 @@
 use clap::{crate_version, Arg, ArgMatches, Command};
 use mdbook::errors::{Error, Result};
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_numeq::NumEqPreprocessor;
+use mdbook_numeq::{escape_annotation, NumEqPreprocessor};
 use semver::{Version, VersionReq};
-use std::io;
+use std::io::{Read, Write};
+use std::{env, io};
+
+/// The oldest version of mdBook whose book format (e.g., optional chapter paths of draft chapters)
+/// can be read by the preprocessor.
+const MIN_MDBOOK_VERSION: &str = "0.4.0";
 
 /// Parse CLI options.
 pub fn make_app() -> Command {
@@ -17,8 +22,65 @@ pub fn make_app() -> Command {
         )
 }
 
+/// Returns the log filter: an explicitly set `RUST_LOG` takes precedence over the `log_level`
+/// option of the preprocessor, and only warnings are shown by default.
+fn log_filter(rust_log: Option<String>, log_level: Option<&str>) -> String {
+    rust_log.unwrap_or_else(|| log_level.unwrap_or("warn").to_string())
+}
+
+/// Initializes the logger; with `annotations`, messages which are annotation commands of a CI service
+/// (e.g., `::warning file=...::message`) are written as is, so that the service can read them.
+fn init_logger(log_level: Option<&str>, annotations: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&log_filter(env::var("RUST_LOG").ok(), log_level));
+    if annotations {
+        builder.format(|buf, record| {
+            let msg = record.args().to_string();
+            if msg.starts_with("::") {
+                writeln!(buf, "{}", escape_annotation(&msg))
+            } else {
+                writeln!(buf, "[{}] {msg}", record.level())
+            }
+        });
+    }
+    builder.init();
+}
+
+/// Returns an error if `version`, the version of mdBook calling the preprocessor, is older than `MIN_MDBOOK_VERSION`.
+fn check_version_floor(version: &str) -> Result<()> {
+    let version = Version::parse(version)?;
+    if VersionReq::parse(&format!(">={MIN_MDBOOK_VERSION}"))?.matches(&version) {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "mdbook {version} is too old for the numeq preprocessor, which requires mdbook {MIN_MDBOOK_VERSION} or later: \
+             please upgrade mdbook (e.g., with `cargo install mdbook`)"
+        )))
+    }
+}
+
 fn handle_preprocessing() -> Result<()> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    // the version is checked before parsing the book, whose format may differ in older versions
+    let version = serde_json::from_str::<serde_json::Value>(&input)?
+        .get(0)
+        .and_then(|ctx| ctx.get("mdbook_version"))
+        .and_then(|v| v.as_str().map(str::to_string));
+    if let Some(version) = version {
+        check_version_floor(&version)?;
+    }
+    let (ctx, book) = CmdPreprocessor::parse_input(input.as_bytes())?;
+
+    let log_level = match ctx.config.get("preprocessor.numeq.log_level") {
+        Some(toml::Value::String(l)) => Some(l.as_str()),
+        _ => None,
+    };
+    let annotations = matches!(
+        ctx.config.get("preprocessor.numeq.ci"),
+        Some(toml::Value::String(ci)) if ci != "none"
+    );
+    init_logger(log_level, annotations);
 
     let pre = NumEqPreprocessor::new(&ctx);
 
@@ -42,6 +104,7 @@ fn handle_preprocessing() -> Result<()> {
 }
 
 fn handle_supports(sub_args: &ArgMatches) -> Result<()> {
+    init_logger(None, false);
     let renderer = sub_args
         .get_one::<String>("renderer")
         .expect("Required argument");
@@ -62,8 +125,6 @@ fn handle_supports(sub_args: &ArgMatches) -> Result<()> {
 }
 
 fn main() -> Result<()> {
-    ::std::env::set_var("RUST_LOG", "warn");
-    env_logger::init();
     let matches = make_app().get_matches();
 
     if let Some(sub_args) = matches.subcommand_matches("supports") {
@@ -74,3 +135,26 @@ fn main() -> Result<()> {
         handle_preprocessing()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_level() {
+        assert_eq!(log_filter(None, None), "warn");
+        assert_eq!(log_filter(None, Some("debug")), "debug");
+        assert_eq!(log_filter(Some("info".to_string()), Some("debug")), "info");
+    }
+
+    #[test]
+    fn version_floor() {
+        assert!(check_version_floor(mdbook::MDBOOK_VERSION).is_ok());
+        assert!(check_version_floor("0.4.0").is_ok());
+        let err = check_version_floor("0.3.7").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("mdbook 0.3.7 is too old for the numeq preprocessor"));
+        assert!(check_version_floor("not a version").is_err());
+    }
+}